@@ -0,0 +1,136 @@
+// A bounded map dropping its least recently used entries, e.g. for caching
+// responses. Not synchronized; share it behind a `Mutex`.
+
+use std::collections::{HashMap, TreeMap};
+use std::hash::Hash;
+
+
+struct Entry<V> {
+    value: V,
+    last_used: u64,
+}
+
+
+pub struct LruCache<K, V> {
+    capacity: uint,
+    entries: HashMap<K, Entry<V>>,
+    // keys by when they were last used, oldest first
+    order: TreeMap<u64, K>,
+    clock: u64,
+}
+
+
+impl <K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: uint) -> LruCache<K, V> {
+        LruCache{
+            capacity: capacity,
+            entries: HashMap::new(),
+            order: TreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    // Looking an entry up counts as using it.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let used = self.tick();
+        match self.entries.find_mut(key) {
+            Some(entry) => {
+                self.order.remove(&entry.last_used);
+                self.order.insert(used, key.clone());
+                entry.last_used = used;
+            },
+            None => return None,
+        }
+        self.entries.find(key).map(|entry| &entry.value)
+    }
+
+    // Inserts or replaces the value for `key`, evicting the least recently
+    // used entry when the cache is full.
+    pub fn put(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return
+        }
+        let used = self.tick();
+        match self.entries.pop(&key) {
+            Some(entry) => { self.order.remove(&entry.last_used); },
+            None => if self.entries.len() >= self.capacity {
+                self.evict();
+            },
+        }
+        self.order.insert(used, key.clone());
+        self.entries.insert(key, Entry{value: value, last_used: used});
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.pop(key).map(|entry| {
+            self.order.remove(&entry.last_used);
+            entry.value
+        })
+    }
+
+    fn evict(&mut self) {
+        let oldest = match self.order.iter().next() {
+            Some((&last_used, _)) => last_used,
+            None => return,
+        };
+        match self.order.pop(&oldest) {
+            Some(key) => { self.entries.pop(&key); },
+            None => {},
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::LruCache;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.put(1u, "one");
+        cache.put(2u, "two");
+        // using 1 leaves 2 as the oldest
+        assert_eq!(cache.get(&1), Some(&"one"));
+        cache.put(3u, "three");
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"one"));
+        assert_eq!(cache.get(&3), Some(&"three"));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cache = LruCache::new(3);
+        for i in range(0u, 10) {
+            cache.put(i, i * 10);
+            assert!(cache.len() <= 3);
+        }
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&6), None);
+        assert_eq!(cache.get(&9), Some(&90));
+    }
+
+    #[test]
+    fn test_replace_and_remove() {
+        let mut cache = LruCache::new(2);
+        cache.put("a", 1i);
+        cache.put("a", 2i);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&"a"), Some(&2));
+        assert_eq!(cache.remove(&"a"), Some(2));
+        assert_eq!(cache.len(), 0);
+
+        let mut empty = LruCache::new(0);
+        empty.put("a", 1i);
+        assert_eq!(empty.get(&"a"), None);
+    }
+}
@@ -166,7 +166,7 @@ pub fn error_page_response(response_code: HTTPResponseCode)
         code: response_code,
         headers: vec![HTTPHeader{
             key: String::from_str("Content-Type"),
-            value: String::from_str("text-html; charset=utf-8")
+            value: String::from_str("text/html; charset=utf-8")
         }],
         content_length: Some(response_content_length as u64),
     };
@@ -10,27 +10,87 @@ extern crate time;
 
 extern crate green;
 
-use std::io::{TcpListener, TcpStream, BufferedReader, BufferedWriter, IoResult, Reader, Buffer, Acceptor, Listener};
+use std::any::AnyRefExt;
+use std::io::fs::{PathExtensions, lstat, unlink};
+use std::io::net::ip::IpAddr;
+use std::io::net::pipe::{UnixListener, UnixAcceptor, UnixStream};
+use std::io::timer::sleep;
+use std::io::{TcpListener, TcpAcceptor, TcpStream, BufferedReader, BufferedWriter, MemReader, IoResult, IoError, Reader, Buffer, Acceptor, Listener, TimedOut, InvalidInput, EndOfFile, FileNotFound, OtherIoError, PathAlreadyExists, TypeFile, TypeUnknown, File, Seek, SeekSet, standard_error};
+use std::cmp::{min, max, Equal};
 use std::collections::HashMap;
-use std::task::{TaskBuilder};
-use std::sync::Arc;
-use time::now;
+use std::num::from_str_radix;
+use std::rand::random;
+use std::slice::bytes::copy_memory;
+use std::str::from_utf8;
+use std::task::{TaskBuilder, Spawner, try, deschedule};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use std::time::duration::Duration;
+use time::{get_time, Timespec};
 
 use green::{SchedPool, PoolConfig, GreenTaskBuilder};
 
+pub mod cache;
+pub mod inflate;
+pub mod md5;
 
-#[deriving(Show)]
+
+#[deriving(Show, Clone, PartialEq)]
 pub enum HTTPMethod {
-    GET, POST, HEAD, NoMethod
+    GET, POST, HEAD, PUT, DELETE, PATCH, CONNECT, OPTIONS, TRACE, NoMethod
+}
+
+
+impl HTTPMethod {
+    // The method as sent on the wire; empty for NoMethod, which stands for
+    // a request that could not be parsed.
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match *self {
+            GET => b"GET",
+            POST => b"POST",
+            HEAD => b"HEAD",
+            PUT => b"PUT",
+            DELETE => b"DELETE",
+            PATCH => b"PATCH",
+            CONNECT => b"CONNECT",
+            OPTIONS => b"OPTIONS",
+            TRACE => b"TRACE",
+            NoMethod => b"",
+        }
+    }
+
+    // Method names are case-sensitive.
+    pub fn from_bytes(method: &[u8]) -> Option<HTTPMethod> {
+        match method {
+            b"GET" => Some(GET),
+            b"POST" => Some(POST),
+            b"HEAD" => Some(HEAD),
+            b"PUT" => Some(PUT),
+            b"DELETE" => Some(DELETE),
+            b"PATCH" => Some(PATCH),
+            b"CONNECT" => Some(CONNECT),
+            b"OPTIONS" => Some(OPTIONS),
+            b"TRACE" => Some(TRACE),
+            _ => None,
+        }
+    }
+}
+
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum HTTPVersion {
+    HTTP10, HTTP11
 }
 
 
-#[deriving(Show)]
+#[deriving(Show, PartialEq)]
 pub enum HTTPResponseCode {
-    HTTP200 = 200,
-    HTTP301 = 301, HTTP302 = 302,
+    HTTP200 = 200, HTTP201 = 201, HTTP202 = 202, HTTP204 = 204, HTTP206 = 206,
+    HTTP301 = 301, HTTP302 = 302, HTTP304 = 304,
     HTTP400 = 400, HTTP401 = 401, HTTP403 = 403, HTTP404 = 404,
-    HTTP500 = 500,
+    HTTP405 = 405, HTTP413 = 413, HTTP414 = 414, HTTP415 = 415, HTTP416 = 416,
+    HTTP426 = 426,
+    HTTP500 = 500, HTTP501 = 501, HTTP502 = 502, HTTP505 = 505,
     HTTPERROR = 0,
 }
 
@@ -38,281 +98,5650 @@ pub enum HTTPResponseCode {
 pub type HTTPHeaders = HashMap<Vec<u8>, Vec<u8>>;
 
 
+// Header lookups ignoring the case of header names, and typed accessors
+// for the most common headers.
+pub trait HTTPHeadersExt {
+    fn get_header(&self, name: &[u8]) -> Option<&[u8]>;
+    // Replaces the header under any spelling of `name`.
+    fn set_header(&mut self, name: &[u8], value: Vec<u8>);
+    fn remove_header(&mut self, name: &[u8]);
+    // Adds `value` after any present under `name`, as a comma separated list.
+    fn append_header(&mut self, name: &[u8], value: Vec<u8>);
+
+    // `None` both when the header is missing and when it is not a
+    // non-negative decimal integer.
+    fn content_length(&self) -> Option<u64>;
+    fn content_type(&self) -> Option<&[u8]>;
+    fn host(&self) -> Option<&[u8]>;
+}
+
+
+impl HTTPHeadersExt for HTTPHeaders {
+    fn get_header(&self, name: &[u8]) -> Option<&[u8]> {
+        self.iter()
+            .find(|&(key, _)| eq_ignore_case(key.as_slice(), name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn set_header(&mut self, name: &[u8], value: Vec<u8>) {
+        self.remove_header(name);
+        self.insert(name.to_vec(), value);
+    }
+
+    fn remove_header(&mut self, name: &[u8]) {
+        let existing: Vec<Vec<u8>> = self.keys()
+            .filter(|key| eq_ignore_case(key.as_slice(), name))
+            .map(|key| key.clone())
+            .collect();
+        for key in existing.iter() {
+            self.remove(key);
+        }
+    }
+
+    fn append_header(&mut self, name: &[u8], value: Vec<u8>) {
+        let combined = match self.get_header(name) {
+            Some(existing) => {
+                let mut combined = existing.to_vec();
+                combined.push_all(b", ");
+                combined.push_all(value.as_slice());
+                combined
+            },
+            None => value,
+        };
+        self.set_header(name, combined);
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        match self.get_header(b"Content-Length") {
+            Some(value) if !value.is_empty() && value.iter().all(|b| b'0' <= *b && *b <= b'9') =>
+                from_utf8(value).and_then(from_str::<u64>),
+            _ => None,
+        }
+    }
+
+    fn content_type(&self) -> Option<&[u8]> {
+        self.get_header(b"Content-Type")
+    }
+
+    fn host(&self) -> Option<&[u8]> {
+        self.get_header(b"Host")
+    }
+}
+
+
+#[deriving(Clone)]
+pub struct HTTPRequest {
+    pub method: HTTPMethod,
+    pub path: Vec<u8>,
+    pub version: HTTPVersion,
+    pub headers: HTTPHeaders,
+    // Copies of the most used headers, looked up once while parsing.
+    pub common: CommonHeaders,
+    // Size of the request line and header block as received.
+    pub header_bytes: uint,
+    // Number of header lines, repeated headers included.
+    pub header_count: uint,
+    // What enclosing Mounts stripped off the front of `path`, for links
+    // back to the path as the client sent it.
+    pub mount_prefix: Vec<u8>,
+}
+
+
+// The headers most handlers look at, extracted from the request's header
+// map. They all stay in the map as well.
+#[deriving(Show, Clone, PartialEq)]
+pub struct CommonHeaders {
+    pub host: Option<Vec<u8>>,
+    pub user_agent: Option<Vec<u8>>,
+    pub content_type: Option<Vec<u8>>,
+    pub content_length: Option<u64>,
+    pub accept: Option<Vec<u8>>,
+    pub connection: Option<Vec<u8>>,
+}
+
+
+impl CommonHeaders {
+    pub fn from_headers(headers: &HTTPHeaders) -> CommonHeaders {
+        let get = |name: &[u8]| headers.get_header(name).map(|value| value.to_vec());
+        CommonHeaders{
+            host: get(b"Host"),
+            user_agent: get(b"User-Agent"),
+            content_type: get(b"Content-Type"),
+            content_length: headers.content_length(),
+            accept: get(b"Accept"),
+            connection: get(b"Connection"),
+        }
+    }
+}
+
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Strictness {
+    Strict, Lenient
+}
+
+
+pub struct ServerConfig {
+    // Whole request line and header block must arrive within this time.
+    pub header_timeout_ms: u64,
+    // Slowest acceptable header transfer, only checked once the head has
+    // been coming in for `header_rate_grace_ms`, so that a slow start alone
+    // does not get a client cut off.
+    pub header_min_bytes_per_sec: u64,
+    pub header_rate_grace_ms: u64,
+    // Longer request targets are answered with 414.
+    pub max_path_length: uint,
+    // Paths with more `/`-separated segments are answered with 414 too,
+    // sparing routers from matching absurdly nested paths.
+    pub max_path_segments: uint,
+    // Accept absolute-form request targets ("GET http://host/path"), as
+    // sent to proxies. Otherwise paths must start with "/".
+    pub allow_absolute_form: bool,
+    // Longest line read anywhere in a request: header lines, which get a 400
+    // beyond it, and chunk size and trailer lines of chunked bodies. The
+    // method and version are capped much shorter, paths by the lower of
+    // this and their own limit.
+    pub max_line_length: uint,
+    // Disables Nagle's algorithm so small streamed writes go out at once.
+    pub tcp_nodelay: bool,
+    // Serve further requests on a connection once a response is done.
+    pub keep_alive: bool,
+    // Whatever is left of a request body once the handler is done with it
+    // is read and thrown away, up to this many bytes, so that the next
+    // request on the connection can be read. Beyond that the connection is
+    // closed instead.
+    pub max_drain_bytes: u64,
+    // Show handler error details on 500 pages. Keep off in production.
+    pub debug: bool,
+    // How forgiving request head parsing is about malformed input.
+    pub strictness: Strictness,
+    // Hold back the headers of non-streaming responses so that they go out
+    // together with the body in as few writes as possible.
+    pub buffer_responses: bool,
+    // How long a connection may sit waiting for the next request head
+    // before it is closed and its task exits.
+    pub idle_timeout_ms: u64,
+    // Requests served in a row on one connection before its task yields,
+    // so that a client pipelining lots of requests cannot hog a worker.
+    // Zero never yields.
+    pub requests_before_yield: uint,
+    // A connection is closed after serving this many requests.
+    pub max_requests_per_connection: Option<uint>,
+    // Sending a response, headers and body, may take at most this long.
+    // A client which stops reading then gets its connection closed instead
+    // of blocking the writer forever. Mind long streaming responses.
+    pub write_timeout_ms: Option<u64>,
+    // Further TCP connections from a client address already holding this
+    // many are closed right after being accepted.
+    pub max_connections_per_ip: Option<uint>,
+    // Responses whose headers exceed either limit are replaced by a 500,
+    // as they are most likely the result of a handler bug.
+    pub max_response_headers: uint,
+    pub max_response_header_bytes: uint,
+    // Longest pause between attempts when accepting connections keeps
+    // failing, e.g. for lack of file descriptors.
+    pub max_accept_backoff_ms: u64,
+    // Reason phrases sent instead of the built-in ones, by status code.
+    // An empty phrase is fine.
+    pub reason_phrases: HashMap<u16, String>,
+    // Log a line for every request. Turning this off also saves formatting
+    // it, e.g. for benchmarks.
+    pub log_requests: bool,
+    // Send how long parsing the request and running the handler took as a
+    // `Server-Timing` header, for browser developer tools. Origins other
+    // than the page's own only get to see it if listed in
+    // `timing_allow_origin`, e.g. "*".
+    pub server_timing: bool,
+    pub timing_allow_origin: Option<String>,
+    // Answer GET and HEAD requests which come with a body with a 400 and
+    // close, rather than dropping the body and going on.
+    pub reject_get_bodies: bool,
+    // Pass request bodies on to handlers as sent, whatever their
+    // Content-Encoding. Otherwise gzip and deflate bodies are decoded before
+    // the handler reads them, and other codings are answered with a 415.
+    pub accept_encoded_bodies: bool,
+    // Decoded request bodies are held in memory, so their size, compressed
+    // or not, is capped. Beyond it requests get a 413.
+    pub max_decoded_body_bytes: uint,
+    // Tells this server's log lines apart from other instances' in
+    // aggregated logs.
+    pub instance_name: String,
+    // Request bodies trickling in slower than this, measured over
+    // `body_rate_window_ms` of waiting for them, fail the handler's reads
+    // and the connection is closed.
+    pub body_min_bytes_per_sec: Option<u64>,
+    pub body_rate_window_ms: u64,
+    // Charset added to text content types of response writers which give
+    // none, such as BytesResponseWriter's.
+    pub default_charset: String,
+    // Where all time measurements come from.
+    pub clock: Box<Clock + Send + Sync>,
+    // Called once every response is done, besides the built-in logging.
+    pub access_log: Option<fn(&AccessLogEntry)>,
+    // Stops the server's accept loops when triggered; keep a clone of it
+    // before handing the config over.
+    pub shutdown: Shutdown,
+}
+
+
+impl ServerConfig {
+    pub fn new() -> ServerConfig {
+        ServerConfig{
+            header_timeout_ms: 10000,
+            header_min_bytes_per_sec: 64,
+            header_rate_grace_ms: 5000,
+            max_path_length: 8192,
+            max_path_segments: 256,
+            allow_absolute_form: false,
+            max_line_length: 8192,
+            tcp_nodelay: true,
+            keep_alive: true,
+            max_drain_bytes: 65536,
+            debug: false,
+            strictness: Strict,
+            buffer_responses: true,
+            idle_timeout_ms: 60000,
+            requests_before_yield: 16,
+            max_requests_per_connection: None,
+            write_timeout_ms: None,
+            max_connections_per_ip: None,
+            max_response_headers: 100,
+            max_response_header_bytes: 65536,
+            max_accept_backoff_ms: 1000,
+            reason_phrases: HashMap::new(),
+            log_requests: true,
+            server_timing: false,
+            timing_allow_origin: None,
+            reject_get_bodies: false,
+            accept_encoded_bodies: false,
+            max_decoded_body_bytes: 10 * 1024 * 1024,
+            instance_name: "httpls".to_string(),
+            body_min_bytes_per_sec: None,
+            body_rate_window_ms: 5000,
+            default_charset: "utf-8".to_string(),
+            clock: box SystemClock,
+            access_log: None,
+            shutdown: Shutdown::new(),
+        }
+    }
+}
+
+
+// Source of the current time for the server, replaceable to make
+// timings deterministic.
+pub trait Clock {
+    fn now(&self) -> Timespec;
+}
+
+
+pub struct SystemClock;
+
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timespec {
+        get_time()
+    }
+}
+
+
+// What `ServerConfig::access_log` gets to know about a served request.
+// The durations are all measured from when the server started waiting
+// for the request head.
+pub struct AccessLogEntry<'a> {
+    pub instance_name: &'a str,
+    pub method: HTTPMethod,
+    pub path: &'a [u8],
+    pub code: HTTPResponseCode,
+    pub request_bytes: uint,
+    // until the request head was parsed
+    pub request_duration: Duration,
+    // until the response headers were sent
+    pub headers_duration: Duration,
+    // until the response body was sent
+    pub end_duration: Duration,
+}
+
+
 pub trait HTTPRequestHandler
     <'req, R: Reader + Send + Sized, W: Writer + Send + Sized>
     : Send + Sized
 {
     fn handle(
         &self,
-        method: HTTPMethod,
-        path: &Vec<u8>,
-        headers: &HTTPHeaders,
-        mut stream: BufferedReader<R>)
+        request: &HTTPRequest,
+        body: &mut BodyReader<R>)
         -> IoResult<Option<(HTTPResponseCode,
                             Box<HTTPHeaders>,
                             Box<HTTPResponseWriter<W> + 'req>)>>;
+
+    // Called before `handle` with the connection right after the request
+    // head. A handler returning true has taken the connection over, e.g.
+    // to speak another protocol after an Upgrade, and has written whatever
+    // it needed to itself; no response is sent and the connection is closed
+    // once it returns.
+    fn take_over(
+        &self,
+        request: &HTTPRequest,
+        reader: &mut BufferedReader<R>,
+        writer: &mut BufferedWriter<W>)
+        -> IoResult<bool>
+    {
+        Ok(false)
+    }
+
+    // Called for requests sent with `Expect: 100-continue` before the client
+    // is told to go on with the body. A response returned here rejects the
+    // request, e.g. for a body too large or missing credentials; the client
+    // then never sends the body and `handle` is not called.
+    fn check_continue(&self, request: &HTTPRequest)
+                      -> IoResult<Option<(HTTPResponseCode,
+                                          Box<HTTPHeaders>,
+                                          Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        Ok(None)
+    }
 }
 
 
 pub trait HTTPResponseWriter<W: Writer + Send + Sized> {
     fn get_content_length(&self) -> Option<u64>;
     fn get_content_type(&self) -> String;
-    fn write_data(&self, mut stream: BufferedWriter<W>) -> IoResult<()>;
+    fn write_data(&self, stream: &mut BufferedWriter<W>) -> IoResult<()>;
+
+    // Whether the headers are flushed before `write_data` is called.
+    // The stream is always flushed once more after `write_data` returns,
+    // so writers which batch their output may turn this off.
+    fn should_autoflush(&self) -> bool {
+        true
+    }
+
+    // Streaming writers produce their body bit by bit over time and are
+    // never held back by `ServerConfig::buffer_responses`.
+    fn is_streaming(&self) -> bool {
+        false
+    }
+
+    // A quoted entity tag for the body. For a 200 response it is sent as
+    // `ETag`, and a request whose `If-None-Match` lists it gets a 304.
+    fn get_etag(&self) -> Option<Vec<u8>> {
+        None
+    }
 }
 
 
 fn update_response_headers<W: Writer + Send + Sized>
     (writer: &HTTPResponseWriter<W>,
-     headers: &mut HTTPHeaders)
+     headers: &mut HTTPHeaders,
+     config: &ServerConfig)
 {
-    let content_type_hdr = b"Content-Type".to_vec();
-    if !headers.contains_key(&content_type_hdr) {
-        headers.insert(content_type_hdr,
-                       writer.get_content_type().into_bytes());
+    // the handler's headers may be spelled in any case
+    if headers.get_header(b"Content-Type").is_none() {
+        let mut content_type = writer.get_content_type();
+        // text whose writer did not say otherwise is taken to be in the
+        // default charset
+        if content_type.as_slice().starts_with("text/")
+            && content_type_param(content_type.as_bytes(), b"charset").is_none()
+        {
+            content_type.push_str("; charset=");
+            content_type.push_str(config.default_charset.as_slice());
+        }
+        headers.set_header(b"Content-Type", content_type.into_bytes());
     }
 
-    let content_length_hdr = b"Content-Length".to_vec();
-    if !headers.contains_key(&content_length_hdr) {
+    if headers.get_header(b"Content-Length").is_none() {
         match writer.get_content_length() {
-            Some(i) => {
-                headers.insert(
-                    content_length_hdr,
-                    i.to_string().into_bytes());
-            },
+            Some(i) => headers.set_header(b"Content-Length", i.to_string().into_bytes()),
             None => {}
         }
     }
 }
 
 
-fn handle_http<'req, R: Reader + Send + Sized, W: Writer + Send + Sized>
+// Streams whose blocking operations can be cut short, so that connections
+// left idle by their clients, or clients which stopped reading, do not hold
+// on to a task forever. Both timeouts count from when they are set.
+pub trait ConnectionTimeouts {
+    fn set_idle_timeout(&mut self, timeout_ms: Option<u64>);
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>);
+}
+
+
+impl ConnectionTimeouts for TcpStream {
+    fn set_idle_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.set_read_timeout(timeout_ms)
+    }
+
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.set_write_timeout(timeout_ms)
+    }
+}
+
+
+impl ConnectionTimeouts for UnixStream {
+    fn set_idle_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.set_read_timeout(timeout_ms)
+    }
+
+    fn set_write_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.set_write_timeout(timeout_ms)
+    }
+}
+
+
+static LIVE_CONNECTIONS: AtomicUint = INIT_ATOMIC_UINT;
+
+
+// Number of connections currently being served.
+pub fn live_connections() -> uint {
+    LIVE_CONNECTIONS.load(SeqCst)
+}
+
+
+// Serves requests from one connection until either side wants it closed.
+fn handle_http
+    <'req, R: Reader + ConnectionTimeouts + Send + Sized,
+     W: Writer + ConnectionTimeouts + Send + Sized>
     (handler: &HTTPRequestHandler<'req, R, W>,
+     config: &ServerConfig,
+     headers_sent: &AtomicBool,
      mut reader: BufferedReader<R>,
      mut writer: BufferedWriter<W>)
      -> IoResult<()>
 {
-    let bad_req = proc
-        (x: IoResult<Option<()>>)
-         -> (IoResult<Option<()>>, HTTPMethod, Box<Vec<u8>>, Box<HTTPHeaders>)
-    {
-        (x, NoMethod, box b"".to_vec(), box HashMap::new())
+    let mut served = 0u;
+    loop {
+        headers_sent.store(false, SeqCst);
+        let remaining = config.max_requests_per_connection.map(|max| max - min(served, max));
+        if !try!(handle_http_request(handler, config, headers_sent, remaining,
+                                     &mut reader, &mut writer)) {
+            return Ok(())
+        }
+        served += 1;
+        if config.requests_before_yield > 0 && served % config.requests_before_yield == 0 {
+            deschedule();
+        }
+    }
+}
+
+
+static BAD_REQUEST_RESPONSE: &'static [u8] =
+    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+
+// Serves a single request and tells whether the connection may be reused.
+// `remaining` is how many requests the connection may still serve,
+// including this one, if limited.
+fn handle_http_request
+    <'req, R: Reader + ConnectionTimeouts + Send + Sized,
+     W: Writer + ConnectionTimeouts + Send + Sized>
+    (handler: &HTTPRequestHandler<'req, R, W>,
+     config: &ServerConfig,
+     headers_sent: &AtomicBool,
+     remaining: Option<uint>,
+     reader: &mut BufferedReader<R>,
+     writer: &mut BufferedWriter<W>)
+     -> IoResult<bool>
+{
+    // waiting for a request on a kept-alive connection is covered by the
+    // idle timeout; the header deadline and the request's timings start
+    // with the first byte of its head
+    let idle_start = config.clock.now();
+    reader.get_mut().set_idle_timeout(Some(config.idle_timeout_ms));
+    match reader.fill_buf() {
+        Ok(_) => {},
+        Err(ref e) if e.kind == EndOfFile => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    let start_time = config.clock.now();
+    // a client stalling halfway through the head is cut off by whichever
+    // deadline comes first, rather than blocking a read forever
+    let idle_waited = max(0, (start_time - idle_start).num_milliseconds()) as u64;
+    let idle_left = config.idle_timeout_ms - min(idle_waited, config.idle_timeout_ms);
+    reader.get_mut().set_idle_timeout(Some(min(config.header_timeout_ms, idle_left)));
+
+    let mut request_result = match parse_http_request(reader, config) {
+        Err(ref e) if e.kind == TimedOut => return Err(e.clone()),
+        Err(ref e) if e.kind == EndOfFile => return Ok(false),
+        other => other,
+    };
+    match request_result {
+        Ok(Ok(ref mut request)) => decline_h2c_upgrade(request),
+        // nothing usable came in, likely not even HTTP; answered without
+        // running the response machinery, as cheaply as possible
+        Ok(Err(HTTP400)) | Err(_) => {
+            try!(writer.write(BAD_REQUEST_RESPONSE));
+            try!(writer.flush());
+            debug!("malformed request - 400 sent");
+            return Ok(false)
+        },
+        _ => {},
+    }
+    // the timeouts only cover the wait for the request head
+    reader.get_mut().set_idle_timeout(None);
+
+    let request_duration = config.clock.now() - start_time;
+
+    let empty_response = |code: HTTPResponseCode| {
+        let writer: Box<HTTPResponseWriter<W>> =
+            BytesResponseWriter::<W>::new(vec![]);
+        Ok(Some((code, box HashMap::new(), writer)))
+    };
+
+    match request_result {
+        Ok(Ok(ref request)) => if try!(handler.take_over(request, reader, writer)) {
+            try!(writer.flush());
+            debug!("{} \"{}\" - connection taken over",
+                   request.method, String::from_utf8_lossy(request.path.as_slice()));
+            return Ok(false)
+        },
+        _ => {},
+    }
+
+    let rejection = match request_result {
+        Ok(Ok(ref request)) if expects_continue(request) => match handler.check_continue(request) {
+            Ok(None) => {
+                try!(writer.write_str("HTTP/1.1 100 Continue\r\n\r\n"));
+                try!(writer.flush());
+                None
+            },
+            other => Some(other),
+        },
+        _ => None,
     };
 
-    let start_time = now().to_timespec();
+    let (handler_result, body_consumed) = match rejection {
+        // the body was never asked for, so the connection cannot be reused
+        Some(result) => (result, false),
+        None => match request_result {
+            Ok(Ok(ref mut request)) => match BodyReader::new(reader, &request.headers, config.max_line_length) {
+                Ok(mut body) => {
+                    match config.body_min_bytes_per_sec {
+                        Some(rate) => body.set_min_rate(
+                            &*config.clock, rate, Duration::milliseconds(config.body_rate_window_ms as i64)),
+                        None => {},
+                    }
+                    // a body sent along with GET or HEAD means nothing, so
+                    // the handler gets to see none
+                    let coding = request.headers.get_header(b"Content-Encoding")
+                        .map(|coding| trim_spaces(coding).to_vec());
+                    let skipped = match (request.method, coding) {
+                        (GET, _) | (HEAD, _) => drain(&mut body, config.max_drain_bytes),
+                        // the handler gets the body decoded, and headers
+                        // describing it as such
+                        (_, Some(ref coding)) if !config.accept_encoded_bodies
+                            && is_decodable_coding(coding.as_slice()) =>
+                            match body.decode(coding.as_slice(), config.max_decoded_body_bytes) {
+                                Ok(Some(length)) => {
+                                    request.headers.remove_header(b"Content-Encoding");
+                                    request.headers.remove_header(b"Transfer-Encoding");
+                                    request.headers.set_header(
+                                        b"Content-Length", length.to_string().into_bytes());
+                                    request.common = CommonHeaders::from_headers(&request.headers);
+                                    Ok(true)
+                                },
+                                Ok(None) => Ok(false),
+                                Err(e) => Err(e),
+                            },
+                        _ => Ok(true),
+                    };
+                    match skipped {
+                        Ok(true) => {
+                            let result = handler.handle(request, &mut body);
+                            // whatever the handler left unread must not be
+                            // taken for the next request, unless there is
+                            // none as the connection gets closed anyway
+                            let closing = match result {
+                                Ok(Some((_, ref headers, _))) => headers.get_header(b"Connection")
+                                    .map_or(false, |value| header_has_token(value, b"close")),
+                                _ => true,
+                            };
+                            (result, !closing && drain(&mut body, config.max_drain_bytes).unwrap_or(false))
+                        },
+                        Ok(false) => (empty_response(HTTP413), false),
+                        Err(_) => (empty_response(HTTP400), false),
+                    }
+                },
+                Err(_) => (empty_response(HTTP400), false),
+            },
+            Ok(Err(code)) => (empty_response(code), false),
+            Err(_) => (empty_response(HTTP400), false),
+        },
+    };
+    let handler_duration = config.clock.now() - start_time - request_duration;
 
-    let (req_ok, request_method, request_path, request_headers) =
-        match parse_http_request(&mut reader) {
-            Ok(Some((m, p, h))) => (Ok(Some(())), m, p, h),
-            Ok(None) => bad_req(Ok(None)),
-            Err(e) => bad_req(Err(e)),
+    let (request_method, request_version, request_path, request_size, keep_alive_allowed) =
+        match request_result {
+            Ok(Ok(ref request)) => (
+                request.method, request.version, request.path.clone(), request.header_bytes,
+                body_consumed && client_allows_keep_alive(request.version, &request.headers)),
+            // answered in the server's own version when the request's is
+            // unknown
+            _ => (NoMethod, HTTP11, vec![], 0, false),
         };
+    let keep_alive_allowed = keep_alive_allowed && remaining.map_or(true, |r| r > 1);
+    let mut keep_alive = keep_alive_allowed && match request_result {
+        Ok(Ok(ref request)) => should_keep_alive(request.version, &request.headers, config),
+        _ => false,
+    };
 
-    let request_duration = now().to_timespec() - start_time;
+    let if_none_match = match request_result {
+        Ok(Ok(ref request)) => request.headers.get_header(b"If-None-Match").map(|v| v.to_vec()),
+        _ => None,
+    };
 
-    let handler_result = match req_ok {
-        Ok(Some(())) => handler.handle(
-            request_method, &*request_path, &*request_headers, reader),
-        Ok(None) => Ok(None),
-        Err(e) => Err(e),
+    let handler_result = match handler_result {
+        Ok(Some((_, ref headers, _))) if !response_headers_fit(&**headers, config) =>
+            Err(IoError{
+                kind: OtherIoError,
+                desc: "too many response headers",
+                detail: Some(format!("{} headers", headers.len())),
+            }),
+        other => other,
     };
 
-    let (response_code, mut response_headers, response_writer) =
+    let (mut response_code, mut response_headers, response_writer) =
         match handler_result {
             Ok(Some((c, h, w))) => (c, h, w),
-            _ => {
+            Err(e) => {
+                error!("Request handler failed: {}", e);
+                keep_alive = false;
+                server_error_response(&e, config)
+            },
+            Ok(None) => {
                 let writer: Box<HTTPResponseWriter<W>> =
-                    box BytesResponseWriter{bytes: vec![]};
+                    BytesResponseWriter::<W>::new(vec![]);
+                keep_alive = false;
                 (HTTP400, box HashMap::new(), writer)
             },
         };
 
-    update_response_headers(&*response_writer, &mut *response_headers);
-    match start_http_response(&mut writer, response_code, &*response_headers) {
-        Ok(_) => {
-            let response_headers_duration = now().to_timespec() - start_time;
-            let result = response_writer.write_data(writer);
-            let response_end_duration = now().to_timespec() - start_time;
-
-            info!("{} \"{}\" - {} (req: {:0.4f}s, resp: {:0.4f}s, end: {:0.4f}s)",
-                  request_method,
-                  match String::from_utf8(*request_path) {
-                      Ok(s) => s,
-                      Err(s) => format!("{}", s),
-                  },
-                  response_code as int,
-                  request_duration.num_milliseconds() as f64 / 1000.0,
-                  response_headers_duration.num_milliseconds() as f64 / 1000.0,
-                  response_end_duration.num_milliseconds() as f64 / 1000.0
-                  );
-            result
+    // a Connection header set by the handler overrides the server's choice,
+    // though it cannot keep alive a connection the client asked to close
+    match response_headers.get_header(b"Connection") {
+        Some(value) if header_has_token(value, b"close") => keep_alive = false,
+        Some(value) if header_has_token(value, b"keep-alive") => keep_alive = keep_alive_allowed,
+        _ => {},
+    }
+    // a 426 is meaningless without the protocols it asks for
+    let upgrade = match response_code {
+        HTTP426 => response_headers.get_header(b"Upgrade").map(|value| value.to_vec()),
+        _ => None,
+    };
+    strip_hop_by_hop_headers(&mut *response_headers);
+
+    if config.server_timing {
+        let milliseconds = |d: Duration| d.num_microseconds().unwrap_or(0) as f64 / 1000.0;
+        response_headers.set_header(
+            b"Server-Timing",
+            format!("parse;dur={:0.3f}, handler;dur={:0.3f}",
+                    milliseconds(request_duration), milliseconds(handler_duration)).into_bytes());
+        match config.timing_allow_origin {
+            Some(ref origin) =>
+                response_headers.set_header(b"Timing-Allow-Origin", origin.clone().into_bytes()),
+            None => {},
+        }
+    }
+
+    // a client already holding the current version gets an empty 304
+    match (response_code, response_writer.get_etag()) {
+        (HTTP200, Some(etag)) => {
+            match if_none_match {
+                Some(ref tags) if etag_matches(tags.as_slice(), etag.as_slice()) =>
+                    response_code = HTTP304,
+                _ => {},
+            }
+            response_headers.set_header(b"ETag", etag);
         },
-        Err(e) => Err(e)
+        _ => {},
     }
-}
 
+    // without a length the end of the body is marked by closing the connection
+    let bodiless = response_code == HTTP204 || response_code == HTTP304;
+    if response_writer.get_content_length().is_none() && !bodiless {
+        keep_alive = match request_method {
+            HEAD => keep_alive,
+            _ => false,
+        };
+    }
 
-#[inline(always)]
-fn parse_http_request<R: Reader + Send + Sized>
-    (reader: &mut BufferedReader<R>)
-     -> IoResult<Option<(
-         HTTPMethod,
-         Box<Vec<u8>>,  // path
-         Box<HTTPHeaders>,  // request headers
-         )>>
-{
-    let request_method = match try!(reader.read_until(b' ')).as_slice() {
-        b"GET " => GET,
-        b"POST " => POST,
-        b"HEAD " => HEAD,
-        _ => return Ok(None),
+    update_response_headers(&*response_writer, &mut *response_headers, config);
+    // validators such as ETag stay
+    if bodiless {
+        response_headers.remove_header(b"Content-Length");
+        response_headers.remove_header(b"Content-Type");
+    }
+    let mut connection = if keep_alive { b"keep-alive".to_vec() } else { b"close".to_vec() };
+    match upgrade {
+        Some(upgrade) => {
+            response_headers.set_header(b"Upgrade", upgrade);
+            connection.push_all(b", upgrade");
+        },
+        None => {},
+    }
+    response_headers.set_header(b"Connection", connection);
+    // tells the client how long and for how many more requests it may
+    // count on the connection
+    if keep_alive {
+        let mut hint = format!("timeout={}", config.idle_timeout_ms / 1000);
+        match remaining {
+            Some(remaining) => hint.push_str(format!(", max={}", remaining - 1).as_slice()),
+            None => {},
+        }
+        response_headers.set_header(b"Keep-Alive", hint.into_bytes());
+    }
+
+    let flush_headers = if config.buffer_responses && !response_writer.is_streaming() {
+        false
+    } else {
+        response_writer.should_autoflush()
     };
 
-    let request_path = {
-        let mut s = box try!(reader.read_until(b' '));
-        s.pop();
-        s
+    writer.get_mut().set_write_timeout(config.write_timeout_ms);
+    let reason_phrase = config.reason_phrases.find(&(response_code as u16)).map(|p| p.as_slice());
+    let result = match start_http_response(writer, request_version, response_code, reason_phrase,
+                                           &*response_headers, flush_headers) {
+        Ok(_) => {
+            headers_sent.store(true, SeqCst);
+            let response_headers_duration = config.clock.now() - start_time;
+            // HEAD responses still carry the writer's Content-Length, 204 and
+            // 304 ones have neither length nor body whatever the writer says
+            let send_body = request_method != HEAD && !bodiless;
+            let result = if send_body {
+                match response_writer.write_data(writer) {
+                    Ok(()) => writer.flush(),
+                    Err(e) => Err(e),
+                }
+            } else {
+                writer.flush()
+            };
+            let response_end_duration = config.clock.now() - start_time;
+
+            if config.log_requests {
+                info!("[{}] {} \"{}\" {}B - {} (req: {:0.4f}s, resp: {:0.4f}s, end: {:0.4f}s)",
+                      config.instance_name,
+                      request_method,
+                      String::from_utf8_lossy(request_path.as_slice()),
+                      request_size,
+                      response_code as int,
+                      request_duration.num_milliseconds() as f64 / 1000.0,
+                      response_headers_duration.num_milliseconds() as f64 / 1000.0,
+                      response_end_duration.num_milliseconds() as f64 / 1000.0
+                      );
+            }
+            match config.access_log {
+                Some(log) => log(&AccessLogEntry{
+                    instance_name: config.instance_name.as_slice(),
+                    method: request_method,
+                    path: request_path.as_slice(),
+                    code: response_code,
+                    request_bytes: request_size,
+                    request_duration: request_duration,
+                    headers_duration: response_headers_duration,
+                    end_duration: response_end_duration,
+                }),
+                None => {},
+            }
+            match result {
+                Ok(()) => Ok(keep_alive),
+                Err(e) => Err(e),
+            }
+        },
+        Err(e) => Err(e)
     };
+    writer.get_mut().set_write_timeout(None);
+    result
+}
+
 
-    match try!(reader.read_until(b'\n')).as_slice() {
-        b"HTTP/1.0\r\n" | b"HTTP/1.1\r\n" => {},
-        _ => return Ok(None),
+// Framing and connection management are up to the server alone, so
+// hop-by-hop headers a handler set, including those its Connection header
+// names, are dropped.
+fn strip_hop_by_hop_headers(headers: &mut HTTPHeaders) {
+    let mut names: Vec<Vec<u8>> = match headers.get_header(b"Connection") {
+        Some(value) => value.split(|b| *b == b',')
+            .map(|name| trim_spaces(name).to_vec())
+            .filter(|name| !name.is_empty())
+            .collect(),
+        None => vec![],
+    };
+    for name in [b"Connection", b"Keep-Alive", b"Transfer-Encoding", b"TE", b"Trailer",
+                 b"Upgrade", b"Proxy-Authenticate", b"Proxy-Connection"].iter() {
+        names.push(name.to_vec());
+    }
+    for name in names.iter() {
+        headers.remove_header(name.as_slice());
     }
+}
 
-    let request_headers = {
-        let mut headers = box HashMap::new();
-        loop {
-            let line = try!(reader.read_until('\n' as u8));
 
-            if (line[line.len() - 2]) != '\r' as u8 { return Ok(None) }
+fn expects_continue(request: &HTTPRequest) -> bool {
+    request.version == HTTP11 && match request.headers.get_header(b"Expect") {
+        Some(value) => eq_ignore_case(trim_spaces(value), b"100-continue"),
+        None => false,
+    }
+}
 
-            if line.len() == 2 { break }
 
-            let colon_pos = match line.iter().position(|b| -> bool { *b == ':' as u8 }) {
-                Some(0) | None => return Ok(None),
-                Some(i) => i,
-            };
+fn response_headers_fit(headers: &HTTPHeaders, config: &ServerConfig) -> bool {
+    let bytes = headers.iter().fold(0u, |total, (key, value)| total + key.len() + value.len() + 4);
+    headers.len() <= config.max_response_headers && bytes <= config.max_response_header_bytes
+}
 
-            let value_start_pos = colon_pos + (
-                match line.iter().skip(colon_pos).position(|b| -> bool { *b == ' ' as u8 }) {
-                    Some(i) => i,
-                    None => return Ok(None),
-                });
 
-            let value = line.slice(value_start_pos, line.len() - 2).to_vec();
-            let mut key = line;
-            key.truncate(colon_pos - 1);
-            headers.insert(key, value);
-        }
-        headers
+// There is no HTTP/2 support, so a request offering an upgrade to cleartext
+// HTTP/2 is served as a plain HTTP/1.1 one, which the client takes as the
+// upgrade being declined. The upgrade headers are dropped so that handlers
+// do not act on them.
+fn decline_h2c_upgrade(request: &mut HTTPRequest) {
+    let is_h2c = match request.headers.get_header(b"Upgrade") {
+        Some(value) => eq_ignore_case(trim_spaces(value), b"h2c"),
+        None => false,
     };
-
-    Ok(Some((request_method,
-             request_path,
-             request_headers,
-             )))
+    if is_h2c {
+        debug!("Declining h2c upgrade of \"{}\"",
+               String::from_utf8_lossy(request.path.as_slice()));
+        request.headers.remove_header(b"Upgrade");
+        request.headers.remove_header(b"HTTP2-Settings");
+    }
 }
 
 
-pub struct BytesResponseWriter {
-    bytes: Vec<u8>
+// Whether the server keeps the connection of a request open for more,
+// unless the handler's response says otherwise. HTTP/1.1 connections are
+// kept open unless the client asks otherwise, HTTP/1.0 ones only when the
+// client asks for it, and none when `ServerConfig::keep_alive` is off.
+pub fn should_keep_alive(version: HTTPVersion, headers: &HTTPHeaders, config: &ServerConfig)
+                         -> bool
+{
+    config.keep_alive && client_allows_keep_alive(version, headers)
 }
 
 
-impl <'a, W: Writer + Send + Sized>BytesResponseWriter {
-    pub fn new(bytes: Vec<u8>) -> Box<HTTPResponseWriter<W> + 'a> {
-        box BytesResponseWriter{bytes: bytes}
+// What is left when a handler asks for keep-alive, overriding the server.
+fn client_allows_keep_alive(version: HTTPVersion, headers: &HTTPHeaders) -> bool {
+    let connection = headers.get_header(b"Connection").unwrap_or(b"");
+    match version {
+        HTTP10 => header_has_token(connection, b"keep-alive"),
+        HTTP11 => !header_has_token(connection, b"close"),
     }
 }
 
 
-impl <W: Writer + Send + Sized>HTTPResponseWriter<W>
-    for BytesResponseWriter
-{
-    fn get_content_length(&self) -> Option<u64> {
-        Some(self.bytes.len() as u64)
+// Whether a comma separated header value lists `token`, ignoring case.
+fn header_has_token(value: &[u8], token: &[u8]) -> bool {
+    value.split(|b| *b == b',').any(|item| eq_ignore_case(trim_spaces(item), token))
+}
+
+
+fn eq_ignore_case(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| ascii_lower(*x) == ascii_lower(*y))
+}
+
+
+fn trim_spaces(bytes: &[u8]) -> &[u8] {
+    let start = match bytes.iter().position(|b| *b != b' ' && *b != b'\t') {
+        Some(i) => i,
+        None => return b"",
+    };
+    let end = bytes.iter().rposition(|b| *b != b' ' && *b != b'\t').unwrap();
+    bytes.slice(start, end + 1)
+}
+
+
+fn ascii_lower(b: u8) -> u8 {
+    match b {
+        b'A' ... b'Z' => b + 32,
+        _ => b,
     }
+}
 
-    fn get_content_type(&self) -> String {
-        "text/html; charset=utf-8".to_string()
+
+fn ascii_upper(b: u8) -> u8 {
+    match b {
+        b'a' ... b'z' => b - 32,
+        _ => b,
     }
+}
 
-    fn write_data(&self, mut stream: BufferedWriter<W>) -> IoResult<()> {
-        stream.write(self.bytes.as_slice())
+
+// Reads `reader` up to its end, telling whether it got there within `max`
+// bytes.
+fn drain<R: Reader>(reader: &mut R, max: u64) -> IoResult<bool> {
+    let mut buf = [0u8, ..4096];
+    let mut total = 0u64;
+    loop {
+        let wanted = min(buf.len() as u64, max - total + 1) as uint;
+        match reader.read(buf.slice_to_mut(wanted)) {
+            Ok(count) => {
+                total += count as u64;
+                if total > max {
+                    return Ok(false)
+                }
+            },
+            Err(ref e) if e.kind == EndOfFile => return Ok(true),
+            Err(e) => return Err(e),
+        }
     }
 }
 
 
-fn start_http_response<W: Writer>
-    (writer: &mut BufferedWriter<W>,
-     response_code: HTTPResponseCode,
-     headers: &HTTPHeaders)
-     -> IoResult<()>
-{
-    try!(writer.write_str("HTTP/1.0 "));
-    try!(writer.write_str(match response_code {
-        HTTP200 => "200 Ok",
-        HTTP400 => "400 Bad Request",
-        HTTP404 => "404 Not Found",
-        _ => "417 I Am A Teapot",
-    }));
-    try!(writer.write_str("\r\n"));
+struct HeaderDeadline<'a> {
+    clock: &'a Clock,
+    start: Timespec,
+    timeout: Duration,
+    min_bytes_per_sec: u64,
+    rate_grace: Duration,
+    bytes: u64,
+}
 
-    for (key, value) in headers.iter() {
-        try!(writer.write(key.as_slice()));
-        try!(writer.write_str(": "));
-        try!(writer.write(value.as_slice()));
-        try!(writer.write_str("\r\n"));
+
+impl <'a> HeaderDeadline<'a> {
+    fn new(config: &'a ServerConfig) -> HeaderDeadline<'a> {
+        HeaderDeadline{
+            clock: &*config.clock,
+            start: config.clock.now(),
+            timeout: Duration::milliseconds(config.header_timeout_ms as i64),
+            min_bytes_per_sec: config.header_min_bytes_per_sec,
+            rate_grace: Duration::milliseconds(config.header_rate_grace_ms as i64),
+            bytes: 0,
+        }
     }
 
-    try!(writer.write_str("\r\n"));
-    try!(writer.flush());
-    Ok(())
+    fn check(&mut self, received: uint) -> IoResult<()> {
+        self.bytes += received as u64;
+        let elapsed = self.clock.now() - self.start;
+        let too_slow = elapsed >= self.rate_grace
+            && self.bytes * 1000 / max(elapsed.num_milliseconds(), 1) as u64 < self.min_bytes_per_sec;
+        if elapsed > self.timeout || too_slow {
+            return Err(IoError{
+                kind: TimedOut,
+                desc: "request headers were not received in time",
+                detail: None,
+            })
+        }
+        Ok(())
+    }
 }
 
 
-pub fn multi_thread_http_serve
-    <'req, T: HTTPRequestHandler<'req, TcpStream, TcpStream> + Send + Sync + Sized>
-    (host: &str, port: u16, handler: Arc<T>)
-     -> IoResult<()>
+// Like `read_until`, but stops allocating once `max` bytes were read without
+// finding `delim` and returns `None` instead. When a header deadline is
+// given, a trickling client is cut off as soon as it is exceeded rather
+// than after the whole line has finally arrived.
+fn read_until_limited<R: Reader>
+    (reader: &mut BufferedReader<R>,
+     delim: u8,
+     max: uint,
+     mut deadline: Option<&mut HeaderDeadline>)
+     -> IoResult<Option<Vec<u8>>>
 {
-    let listener = TcpListener::bind(host, port);
-    let mut acceptor = listener.listen();
-
-    for stream in acceptor.incoming() {
-        let stream = try!(stream);
-        let new_handler = handler.clone();
-
-        spawn(proc() {
-            let reader = BufferedReader::new(stream.clone());
-            let writer = BufferedWriter::new(stream);
-            let result: IoResult<()> = handle_http(&*new_handler, reader, writer);
-            result.unwrap()
-        });
+    let mut result = Vec::new();
+    loop {
+        let (done, used) = {
+            let available = try!(reader.fill_buf());
+            // result.len() < max here, as reaching max ends the loop below
+            let window = available.slice_to(min(available.len(), max - result.len()));
+            match window.iter().position(|b| *b == delim) {
+                Some(i) => {
+                    result.push_all(window.slice_to(i + 1));
+                    (true, i + 1)
+                },
+                None => {
+                    result.push_all(window);
+                    (false, window.len())
+                },
+            }
+        };
+        reader.consume(used);
+        match deadline {
+            Some(ref mut d) => try!(d.check(used)),
+            None => {},
+        }
+        if done { return Ok(Some(result)) }
+        if result.len() >= max || used == 0 { return Ok(None) }
     }
-    Ok(())
 }
 
 
-pub fn green_http_serve
-    <'req, T: HTTPRequestHandler<'req, TcpStream, TcpStream> + Send + Sync + Sized>
-    (host: &str, port: u16, handler: Arc<T>)
-     -> IoResult<()>
+#[inline(always)]
+fn parse_http_request<R: Reader + Send + Sized>
+    (reader: &mut BufferedReader<R>,
+     config: &ServerConfig)
+     -> IoResult<Result<HTTPRequest, HTTPResponseCode>>
 {
-    let mut pool = SchedPool::new(PoolConfig::new());
+    // the caller waited for the head's first byte, under the idle timeout
+    let mut deadline = HeaderDeadline::new(config);
 
-    let listener = TcpListener::bind(host, port);
-    let mut acceptor = listener.listen();
-
-    for stream in acceptor.incoming() {
-        let stream = try!(stream);
-        let new_handler = handler.clone();
+    let method_token = match try!(read_until_limited(reader, b' ', 16, Some(&mut deadline))) {
+        Some(token) => token,
+        None => return Ok(Err(HTTP400)),
+    };
+    let request_method = match parse_method(method_token.slice_to(method_token.len() - 1)) {
+        Ok(method) => method,
+        Err(code) => return Ok(Err(code)),
+    };
 
-        TaskBuilder::new().green(&mut pool).spawn(proc() {
-            let reader = BufferedReader::new(stream.clone());
-            let writer = BufferedWriter::new(stream);
-            let result: IoResult<()> = handle_http(&*new_handler, reader, writer);
-            result.unwrap()
-        });
-    }
-    Ok(())
+    let request_path = {
+        let mut s = match try!(read_until_limited(
+            reader, b' ', min(config.max_path_length, config.max_line_length), Some(&mut deadline))) {
+            Some(s) => s,
+            None => return Ok(Err(HTTP414)),
+        };
+        s.pop();
+        s
+    };
+    if !is_valid_target(request_method, request_path.as_slice(), config.allow_absolute_form) {
+        return Ok(Err(HTTP400))
+    }
+    let segment_count = {
+        let (path_only, _) = split_query(request_path.as_slice());
+        path_only.iter().filter(|b| **b == b'/').count()
+    };
+    if segment_count > config.max_path_segments {
+        return Ok(Err(HTTP414))
+    }
+
+    let version = match try!(read_until_limited(reader, b'\n', 16, Some(&mut deadline))) {
+        Some(version) => version,
+        None => return Ok(Err(HTTP400)),
+    };
+    let version = match strip_line_ending(version.as_slice(), config.strictness) {
+        Some(version) => version,
+        None => return Ok(Err(HTTP400)),
+    };
+    let request_version = match parse_version(version, config.strictness) {
+        Ok(version) => version,
+        Err(code) => return Ok(Err(code)),
+    };
+
+    let mut header_count = 0u;
+    let mut host_count = 0u;
+    let request_headers = {
+        let mut headers = HashMap::new();
+        loop {
+            let raw_line = match try!(read_until_limited(
+                reader, b'\n', config.max_line_length, Some(&mut deadline))) {
+                Some(line) => line,
+                None => return Ok(Err(HTTP400)),
+            };
+            let line = match strip_line_ending(raw_line.as_slice(), config.strictness) {
+                Some(line) => line,
+                None => return Ok(Err(HTTP400)),
+            };
+
+            if line.is_empty() { break }
+            header_count += 1;
+
+            let (name, value) = match split_header_line(line, config.strictness) {
+                Some(header) => header,
+                None => return Ok(Err(HTTP400)),
+            };
+            if eq_ignore_case(name, b"Host") {
+                host_count += 1;
+            }
+            headers.insert(name.to_vec(), value.to_vec());
+        }
+        headers
+    };
+    if !host_count_valid(request_version, host_count) {
+        return Ok(Err(HTTP400))
+    }
+
+    // the host in an absolute-form target wins over the Host header; the
+    // handler gets to see the request as if it had been sent in origin-form
+    let mut request_path = request_path;
+    let mut request_headers = request_headers;
+    if is_absolute_form(request_path.as_slice()) {
+        let (authority, path) = split_absolute_form(request_path.as_slice());
+        match request_headers.host() {
+            Some(host) if !eq_ignore_case(host, authority.as_slice()) =>
+                debug!("Host \"{}\" overridden by the request target's \"{}\"",
+                       String::from_utf8_lossy(host), String::from_utf8_lossy(authority.as_slice())),
+            _ => {},
+        }
+        request_headers.set_header(b"Host", authority);
+        request_path = path;
+    }
+
+    let has_body = request_headers.get_header(b"Transfer-Encoding").is_some()
+        || request_headers.content_length().map_or(false, |length| length > 0);
+
+    // the body of a GET or HEAD is where requests get smuggled past proxies
+    // which ignore it
+    if config.reject_get_bodies && (request_method == GET || request_method == HEAD) && has_body {
+        return Ok(Err(HTTP400))
+    }
+
+    // a handler reading a body in a coding that cannot be undone here would
+    // take the encoded bytes for the content itself
+    if !config.accept_encoded_bodies && has_body {
+        match request_headers.get_header(b"Content-Encoding") {
+            Some(coding) if !eq_ignore_case(trim_spaces(coding), b"identity")
+                && !is_decodable_coding(trim_spaces(coding)) => return Ok(Err(HTTP415)),
+            _ => {},
+        }
+    }
+
+    Ok(Ok(HTTPRequest{
+        method: request_method,
+        path: request_path,
+        version: request_version,
+        common: CommonHeaders::from_headers(&request_headers),
+        headers: request_headers,
+        header_bytes: deadline.bytes as uint,
+        header_count: header_count,
+        mount_prefix: vec![],
+    }))
+}
+
+
+// A request head parsed in place, borrowing everything from the buffer it
+// was parsed from; see `parse_request_head`.
+pub struct BorrowedRequest<'a> {
+    pub method: HTTPMethod,
+    pub path: &'a [u8],
+    pub version: HTTPVersion,
+    pub headers: Vec<(&'a [u8], &'a [u8])>,
+}
+
+
+// Parses a request head already read into `buf` without copying any of it,
+// for code which only needs to look at the request. Gives `None` while the
+// head is incomplete, otherwise the request and the length of its head.
+// Validation is the same as in `parse_http_request`, minus the length
+// limits, which are up to whoever filled the buffer, and with absolute-form
+// request targets always accepted.
+pub fn parse_request_head<'a>(buf: &'a [u8], strictness: Strictness)
+                              -> Result<Option<(BorrowedRequest<'a>, uint)>, HTTPResponseCode>
+{
+    let mut offset = 0u;
+    let request_line = match next_line(buf, &mut offset) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let request_line = match strip_line_ending(request_line, strictness) {
+        Some(line) => line,
+        None => return Err(HTTP400),
+    };
+    let mut parts = request_line.splitn(2, |b| *b == b' ');
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) => (method, path, version),
+        _ => return Err(HTTP400),
+    };
+    let method = match parse_method(method) {
+        Ok(method) => method,
+        Err(code) => return Err(code),
+    };
+    if !is_valid_target(method, path, true) {
+        return Err(HTTP400)
+    }
+    let version = match parse_version(version, strictness) {
+        Ok(version) => version,
+        Err(code) => return Err(code),
+    };
+
+    let mut headers = Vec::with_capacity(16);
+    loop {
+        let line = match next_line(buf, &mut offset) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let line = match strip_line_ending(line, strictness) {
+            Some(line) => line,
+            None => return Err(HTTP400),
+        };
+        if line.is_empty() { break }
+        match split_header_line(line, strictness) {
+            Some(header) => headers.push(header),
+            None => return Err(HTTP400),
+        }
+    }
+    let host_count = headers.iter().filter(|&&(name, _)| eq_ignore_case(name, b"Host")).count();
+    if !host_count_valid(version, host_count) {
+        return Err(HTTP400)
+    }
+
+    Ok(Some((BorrowedRequest{method: method, path: path, version: version, headers: headers},
+             offset)))
+}
+
+
+// More than one Host header is how requests get routed differently by a
+// proxy and the server behind it; HTTP/1.1 requires exactly one.
+fn host_count_valid(version: HTTPVersion, host_count: uint) -> bool {
+    match version {
+        HTTP10 => host_count <= 1,
+        HTTP11 => host_count == 1,
+    }
+}
+
+
+// The line starting at `offset`, LF included, moving `offset` past it.
+fn next_line<'a>(buf: &'a [u8], offset: &mut uint) -> Option<&'a [u8]> {
+    let rest = buf.slice_from(*offset);
+    rest.iter().position(|b| *b == b'\n').map(|i| {
+        *offset += i + 1;
+        rest.slice_to(i + 1)
+    })
+}
+
+
+// Request targets are paths, "*" for server-wide OPTIONS, "host:port" for
+// CONNECT, or full URLs when proxying.
+fn is_valid_target(method: HTTPMethod, target: &[u8], allow_absolute_form: bool) -> bool {
+    match method {
+        CONNECT => !target.is_empty() && !target.starts_with(b"/"),
+        OPTIONS if target == b"*" => true,
+        _ => target.starts_with(b"/") || (allow_absolute_form && is_absolute_form(target)),
+    }
+}
+
+
+fn is_absolute_form(target: &[u8]) -> bool {
+    let starts_with_ignore_case = |prefix: &[u8]| {
+        target.len() > prefix.len() && eq_ignore_case(target.slice_to(prefix.len()), prefix)
+    };
+    starts_with_ignore_case(b"http://") || starts_with_ignore_case(b"https://")
+}
+
+
+// Splits "http://user@host:port/path?query" into "host:port" and
+// "/path?query".
+fn split_absolute_form(target: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let rest = match target.windows(3).position(|w| w == b"://") {
+        Some(i) => target.slice_from(i + 3),
+        None => target,
+    };
+    let authority_end = rest.iter().position(|b| *b == b'/' || *b == b'?').unwrap_or(rest.len());
+    let authority = rest.slice_to(authority_end);
+    let authority = match authority.iter().rposition(|b| *b == b'@') {
+        Some(i) => authority.slice_from(i + 1),
+        None => authority,
+    };
+    let mut path = rest.slice_from(authority_end).to_vec();
+    if !path.starts_with(b"/") {
+        path.insert(0, b'/');
+    }
+    (authority.to_vec(), path)
+}
+
+
+// Methods are made of uppercase letters only. Anything else, such as a
+// leading space or a tab in place of the space after the method, is a
+// malformed request line which downstream servers might split differently,
+// and gets a 400. Well-formed methods the server does not know get a 501.
+fn parse_method(token: &[u8]) -> Result<HTTPMethod, HTTPResponseCode> {
+    if token.is_empty() || !token.iter().all(|b| b'A' <= *b && *b <= b'Z') {
+        return Err(HTTP400)
+    }
+    match HTTPMethod::from_bytes(token) {
+        Some(method) => Ok(method),
+        None => Err(HTTP501),
+    }
+}
+
+
+// Well-formed versions other than 1.0 and 1.1, such as HTTP/2.0 spoken
+// with prior knowledge, are told apart from garbage with a 505.
+fn parse_version(version: &[u8], strictness: Strictness) -> Result<HTTPVersion, HTTPResponseCode> {
+    let version = match strictness {
+        Strict => version,
+        Lenient => trim_spaces(version),
+    };
+    match version {
+        b"HTTP/1.0" => Ok(HTTP10),
+        b"HTTP/1.1" => Ok(HTTP11),
+        [b'H', b'T', b'T', b'P', b'/', b'0' ... b'9', b'.', b'0' ... b'9'] => Err(HTTP505),
+        _ => Err(HTTP400),
+    }
+}
+
+
+// Splits a header line, line ending already stripped, into name and value.
+fn split_header_line(line: &[u8], strictness: Strictness) -> Option<(&[u8], &[u8])> {
+    let colon_pos = match line.iter().position(|b| *b == b':') {
+        Some(0) | None => return None,
+        Some(i) => i,
+    };
+    // "Host : x" or a name with control bytes in it is how requests
+    // get smuggled past proxies parsing them differently
+    if !line.slice_to(colon_pos).iter().all(|b| is_token_byte(*b)) {
+        return None
+    }
+
+    let rest = line.slice_from(colon_pos + 1);
+    let value = match strictness {
+        Strict => {
+            if !rest.is_empty() && rest[0] != b' ' { return None }
+            let value = rest.slice_from(min(1, rest.len()));
+            if value.ends_with(b" ") || value.ends_with(b"\t") {
+                return None
+            }
+            value
+        },
+        Lenient => trim_spaces(rest),
+    };
+    Some((line.slice_to(colon_pos), value))
+}
+
+
+fn is_token_byte(b: u8) -> bool {
+    match b {
+        b'a' ... b'z' | b'A' ... b'Z' | b'0' ... b'9'
+            | b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.'
+            | b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => false,
+    }
+}
+
+
+// Strict parsing needs CRLF line endings and exactly one space between a
+// header's colon and its value. Lenient parsing also takes bare LF line
+// endings, any amount of whitespace around header values, and trailing
+// whitespace after the HTTP version.
+fn strip_line_ending(line: &[u8], strictness: Strictness) -> Option<&[u8]> {
+    if line.ends_with(b"\r\n") {
+        Some(line.slice_to(line.len() - 2))
+    } else {
+        match strictness {
+            Lenient if line.ends_with(b"\n") => Some(line.slice_to(line.len() - 1)),
+            _ => None,
+        }
+    }
+}
+
+
+// Content codings BodyReader::decode can undo.
+fn is_decodable_coding(coding: &[u8]) -> bool {
+    eq_ignore_case(coding, b"gzip") || eq_ignore_case(coding, b"x-gzip")
+        || eq_ignore_case(coding, b"deflate")
+}
+
+
+fn malformed_body_error() -> IoError {
+    IoError{
+        kind: InvalidInput,
+        desc: "malformed request body framing",
+        detail: None,
+    }
+}
+
+
+// Reads a request body straight from the connection, without buffering it.
+// Reading stops at the end of the body as declared by `Content-Length` or by
+// the final chunk of a chunked body, so whatever follows it on the
+// connection is left in place for the next request.
+pub struct BodyReader<'a, R: 'a> {
+    reader: &'a mut BufferedReader<R>,
+    chunked: bool,
+    remaining: u64,  // in the whole body, or in the current chunk
+    chunk_started: bool,
+    done: bool,
+    max_line_length: uint,
+    min_rate: Option<MinRate<'a>>,
+    // the whole body once decoded, read instead of the connection
+    decoded: Option<MemReader>,
+}
+
+
+// Time spent waiting on the connection for body bytes, and how many came
+// in meanwhile, since the window started.
+struct MinRate<'a> {
+    clock: &'a Clock,
+    min_bytes_per_sec: u64,
+    window: Duration,
+    waited: Duration,
+    bytes: u64,
+}
+
+
+impl <'a, R: Reader> BodyReader<'a, R> {
+    pub fn new(reader: &'a mut BufferedReader<R>, headers: &HTTPHeaders, max_line_length: uint)
+               -> IoResult<BodyReader<'a, R>>
+    {
+        let chunked = match headers.get_header(b"Transfer-Encoding") {
+            Some(value) => !eq_ignore_case(value, b"identity"),
+            None => false,
+        };
+        let length = if chunked { 0 } else {
+            match (headers.get_header(b"Content-Length"), headers.content_length()) {
+                (None, _) => 0,
+                (Some(_), Some(length)) => length,
+                (Some(_), None) => return Err(malformed_body_error()),
+            }
+        };
+        Ok(BodyReader{
+            reader: reader,
+            chunked: chunked,
+            remaining: length,
+            chunk_started: false,
+            done: !chunked && length == 0,
+            max_line_length: max_line_length,
+            min_rate: None,
+            decoded: None,
+        })
+    }
+
+    // Reads the whole body and undoes `coding`, gzip or deflate, so that
+    // further reads give the decoded content. Gives its length, or None
+    // when either the body or its decoded content is over `max_size`.
+    pub fn decode(&mut self, coding: &[u8], max_size: uint) -> IoResult<Option<uint>> {
+        let mut raw = vec![];
+        let mut buf = [0u8, ..4096];
+        loop {
+            match self.read(buf) {
+                Ok(count) => {
+                    if raw.len() + count > max_size {
+                        return Ok(None)
+                    }
+                    raw.push_all(buf.slice_to(count));
+                },
+                Err(ref e) if e.kind == EndOfFile => break,
+                Err(e) => return Err(e),
+            }
+        }
+        // deflate is meant to be zlib wrapped, but some clients send it raw
+        let decoded = if !eq_ignore_case(coding, b"deflate") {
+            inflate::gunzip(raw.as_slice(), max_size)
+        } else if inflate::is_zlib_header(raw.as_slice()) {
+            inflate::zlib_decode(raw.as_slice(), max_size)
+        } else {
+            inflate::inflate(raw.as_slice(), max_size)
+        };
+        match decoded {
+            Ok(content) => {
+                let length = content.len();
+                self.decoded = Some(MemReader::new(content));
+                Ok(Some(length))
+            },
+            Err(inflate::TooLarge) => Ok(None),
+            Err(inflate::Malformed) => Err(malformed_body_error()),
+        }
+    }
+
+    // Fails reads with TimedOut once the body arrives slower than
+    // `min_bytes_per_sec`, averaged over `window` of waiting for it. Time
+    // the handler spends between reads does not count.
+    pub fn set_min_rate(&mut self, clock: &'a Clock, min_bytes_per_sec: u64, window: Duration) {
+        self.min_rate = Some(MinRate{
+            clock: clock,
+            min_bytes_per_sec: min_bytes_per_sec,
+            window: window,
+            waited: Duration::zero(),
+            bytes: 0,
+        });
+    }
+
+    fn read_measured(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let rate = match self.min_rate {
+            Some(ref mut rate) => rate,
+            None => return self.reader.read(buf),
+        };
+        let started = rate.clock.now();
+        let count = try!(self.reader.read(buf));
+        rate.waited = rate.waited + (rate.clock.now() - started);
+        rate.bytes += count as u64;
+        if rate.waited >= rate.window {
+            let milliseconds = max(rate.waited.num_milliseconds(), 1) as u64;
+            if rate.bytes * 1000 / milliseconds < rate.min_bytes_per_sec {
+                return Err(IoError{
+                    kind: TimedOut,
+                    desc: "request body arrived too slowly",
+                    detail: None,
+                })
+            }
+            rate.waited = Duration::zero();
+            rate.bytes = 0;
+        }
+        Ok(count)
+    }
+
+    fn read_line(&mut self) -> IoResult<Vec<u8>> {
+        match read_until_limited(self.reader, b'\n', self.max_line_length, None) {
+            Ok(Some(ref line)) if line.as_slice().ends_with(b"\r\n") => Ok(line.clone()),
+            // the connection ending within the body truncated it
+            Err(ref e) if e.kind != EndOfFile => Err(e.clone()),
+            _ => Err(malformed_body_error()),
+        }
+    }
+
+    fn read_chunk_size(&mut self) -> IoResult<u64> {
+        if self.chunk_started {
+            // every chunk's data is followed by CRLF
+            if try!(self.read_line()).len() != 2 {
+                return Err(malformed_body_error())
+            }
+        }
+        self.chunk_started = true;
+
+        // chunk extensions ("1a;name=value") are ignored
+        let line = try!(self.read_line());
+        let line = line.slice_to(line.len() - 2);
+        let size = match line.iter().position(|b| *b == b';') {
+            Some(i) => line.slice_to(i),
+            None => line,
+        };
+        match from_utf8(size).map(|s| s.trim()).and_then(|s| from_str_radix::<u64>(s, 16)) {
+            Some(size) => Ok(size),
+            None => Err(malformed_body_error()),
+        }
+    }
+}
+
+
+impl <'a, R: Reader> Reader for BodyReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        match self.decoded {
+            Some(ref mut decoded) => return decoded.read(buf),
+            None => {},
+        }
+        if self.done {
+            return Err(standard_error(EndOfFile))
+        }
+
+        if self.remaining == 0 {
+            self.remaining = try!(self.read_chunk_size());
+            if self.remaining == 0 {
+                // skip trailers up to the blank line ending the body
+                while try!(self.read_line()).len() != 2 {}
+                self.done = true;
+                return Err(standard_error(EndOfFile))
+            }
+        }
+
+        let wanted = min(buf.len() as u64, self.remaining) as uint;
+        let count = match self.read_measured(buf.slice_to_mut(wanted)) {
+            Ok(count) => count,
+            // the connection ended before the body did
+            Err(ref e) if e.kind == EndOfFile => return Err(malformed_body_error()),
+            Err(e) => return Err(e),
+        };
+        self.remaining -= count as u64;
+        if !self.chunked && self.remaining == 0 {
+            self.done = true;
+        }
+        Ok(count)
+    }
+}
+
+
+// Reads the whole body and decodes it by the charset of its Content-Type,
+// UTF-8 when none is given. Bytes which are not valid in that charset are
+// an error, never silently replaced.
+pub fn read_body_text<R: Reader>(body: &mut BodyReader<R>, headers: &HTTPHeaders)
+                                 -> IoResult<String>
+{
+    let charset = headers.content_type()
+        .and_then(|content_type| content_type_param(content_type, b"charset"))
+        .unwrap_or(b"utf-8".to_vec());
+    let charset = charset.as_slice();
+
+    let bytes = try!(body.read_to_end());
+    if eq_ignore_case(charset, b"utf-8") || eq_ignore_case(charset, b"utf8") {
+        String::from_utf8(bytes).map_err(|_| IoError{
+            kind: InvalidInput,
+            desc: "request body is not valid UTF-8",
+            detail: None,
+        })
+    } else if eq_ignore_case(charset, b"iso-8859-1") || eq_ignore_case(charset, b"latin1")
+        || eq_ignore_case(charset, b"latin-1")
+    {
+        Ok(bytes.iter().map(|b| *b as char).collect())
+    } else {
+        Err(IoError{
+            kind: InvalidInput,
+            desc: "unsupported request body charset",
+            detail: Some(String::from_utf8_lossy(charset).into_string()),
+        })
+    }
+}
+
+
+// Splits a Content-Type header value into its media type and parameters.
+// The media type and parameter names are lowercased, quoted parameter
+// values are unquoted. Parameters without a value are skipped.
+pub fn parse_content_type(value: &[u8]) -> (Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>) {
+    let (media_type, mut rest) = match value.iter().position(|b| *b == b';') {
+        Some(i) => (value.slice_to(i), value.slice_from(i + 1)),
+        None => (value, value.slice_from(value.len())),
+    };
+
+    let mut params = Vec::new();
+    loop {
+        let equals = match rest.iter().position(|b| *b == b'=' || *b == b';') {
+            Some(i) if rest[i] == b'=' => i,
+            Some(i) => {
+                rest = rest.slice_from(i + 1);
+                continue
+            },
+            None => break,
+        };
+        let name = trim_spaces(rest.slice_to(equals));
+        let after = trim_spaces(rest.slice_from(equals + 1));
+
+        let (param_value, next) = if after.starts_with(b"\"") {
+            let mut unquoted = Vec::new();
+            let mut escaped = false;
+            let mut end = after.len();
+            for (i, b) in after.iter().enumerate().skip(1) {
+                match *b {
+                    _ if escaped => {
+                        unquoted.push(*b);
+                        escaped = false;
+                    },
+                    b'\\' => escaped = true,
+                    b'"' => {
+                        end = i + 1;
+                        break
+                    },
+                    _ => unquoted.push(*b),
+                }
+            }
+            (unquoted, after.slice_from(end))
+        } else {
+            let end = after.iter().position(|b| *b == b';').unwrap_or(after.len());
+            (trim_spaces(after.slice_to(end)).to_vec(), after.slice_from(end))
+        };
+
+        if !name.is_empty() {
+            params.push((name.iter().map(|b| ascii_lower(*b)).collect(), param_value));
+        }
+        rest = match next.iter().position(|b| *b == b';') {
+            Some(i) => next.slice_from(i + 1),
+            None => break,
+        };
+    }
+
+    (trim_spaces(media_type).iter().map(|b| ascii_lower(*b)).collect(), params)
+}
+
+
+// The value of the `name` parameter of a Content-Type header value.
+fn content_type_param(content_type: &[u8], name: &[u8]) -> Option<Vec<u8>> {
+    let (_, params) = parse_content_type(content_type);
+    params.into_iter()
+        .find(|&(ref key, _)| eq_ignore_case(key.as_slice(), name))
+        .map(|(_, value)| value)
+}
+
+
+// The boundary of a multipart body, from its Content-Type header value.
+pub fn multipart_boundary(content_type: &[u8]) -> Option<Vec<u8>> {
+    let (media_type, params) = parse_content_type(content_type);
+    if !media_type.as_slice().starts_with(b"multipart/") {
+        return None
+    }
+    params.into_iter()
+        .find(|&(ref key, ref value)| key.as_slice() == b"boundary" && !value.is_empty())
+        .map(|(_, value)| value)
+}
+
+
+static MAX_PART_HEADER_LINE: uint = 8192;
+
+
+// Reads a multipart body part by part, keeping no more than a few kilobytes
+// of it in memory. `next_part` skips to the next part and returns its
+// headers; the part's content is then read from the MultipartReader itself,
+// which reports EndOfFile where the part ends. Call `next_part` once before
+// reading the first part.
+pub struct MultipartReader<R> {
+    reader: R,
+    // CRLF, "--" and the boundary
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    eof: bool,
+    in_part: bool,
+    done: bool,
+}
+
+
+impl <R: Reader> MultipartReader<R> {
+    pub fn new(reader: R, boundary: &[u8]) -> MultipartReader<R> {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.push_all(boundary);
+        MultipartReader{
+            reader: reader,
+            delimiter: delimiter,
+            // the first delimiter may come without a preamble and so
+            // without the line break before it
+            buf: b"\r\n".to_vec(),
+            eof: false,
+            // the preamble is read through like a part
+            in_part: true,
+            done: false,
+        }
+    }
+
+    pub fn next_part(&mut self) -> IoResult<Option<HTTPHeaders>> {
+        if self.done {
+            return Ok(None)
+        }
+
+        let mut chunk = [0u8, ..4096];
+        loop {
+            match self.read(chunk) {
+                Ok(_) => {},
+                Err(ref e) if e.kind == EndOfFile => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        // the buffer now starts with a delimiter
+        let delimiter_length = self.delimiter.len();
+        try!(self.fill(delimiter_length + 2));
+        self.consume(delimiter_length);
+        if self.buf.as_slice().starts_with(b"--") {
+            self.done = true;
+            return Ok(None)
+        }
+
+        // whitespace may pad the delimiter line
+        if !trim_spaces(try!(self.read_part_line()).as_slice()).is_empty() {
+            return Err(malformed_body_error())
+        }
+        let mut headers = HashMap::new();
+        loop {
+            let line = try!(self.read_part_line());
+            if line.is_empty() { break }
+            match line.iter().position(|b| *b == b':') {
+                Some(i) if i > 0 => {
+                    headers.insert(trim_spaces(line.slice_to(i)).to_vec(),
+                                   trim_spaces(line.slice_from(i + 1)).to_vec());
+                },
+                _ => return Err(malformed_body_error()),
+            }
+        }
+        self.in_part = true;
+        Ok(Some(headers))
+    }
+
+    // Reads until at least `wanted` bytes are buffered or the body ends.
+    fn fill(&mut self, wanted: uint) -> IoResult<()> {
+        let mut chunk = [0u8, ..4096];
+        while self.buf.len() < wanted && !self.eof {
+            match self.reader.read(chunk) {
+                Ok(count) => self.buf.push_all(chunk.slice_to(count)),
+                Err(ref e) if e.kind == EndOfFile => self.eof = true,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn consume(&mut self, count: uint) {
+        let rest = self.buf.slice_from(min(count, self.buf.len())).to_vec();
+        self.buf = rest;
+    }
+
+    fn read_part_line(&mut self) -> IoResult<Vec<u8>> {
+        loop {
+            let position = find_bytes(self.buf.as_slice(), b"\r\n");
+            match position {
+                Some(i) => {
+                    let line = self.buf.slice_to(i).to_vec();
+                    self.consume(i + 2);
+                    return Ok(line)
+                },
+                None if self.eof || self.buf.len() > MAX_PART_HEADER_LINE =>
+                    return Err(malformed_body_error()),
+                None => {
+                    let wanted = self.buf.len() + 1;
+                    try!(self.fill(wanted));
+                },
+            }
+        }
+    }
+}
+
+
+impl <R: Reader> Reader for MultipartReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if !self.in_part {
+            return Err(standard_error(EndOfFile))
+        }
+
+        // enough to tell whether a delimiter starts anywhere in what is
+        // handed out
+        let wanted = self.delimiter.len() + buf.len();
+        try!(self.fill(wanted));
+        let position = find_bytes(self.buf.as_slice(), self.delimiter.as_slice());
+        let available = match position {
+            Some(0) => {
+                self.in_part = false;
+                return Err(standard_error(EndOfFile))
+            },
+            Some(i) => i,
+            None if self.eof => return Err(malformed_body_error()),
+            None => self.buf.len() + 1 - self.delimiter.len(),
+        };
+
+        let count = min(available, buf.len());
+        copy_memory(buf, self.buf.slice_to(count));
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<uint> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+
+pub struct BytesResponseWriter {
+    bytes: Vec<u8>,
+    etag: Option<Vec<u8>>,
+}
+
+
+impl <'a, W: Writer + Send + Sized>BytesResponseWriter {
+    pub fn new(bytes: Vec<u8>) -> Box<HTTPResponseWriter<W> + 'a> {
+        box BytesResponseWriter{bytes: bytes, etag: None}
+    }
+
+    // For bytes served over and over, where `etag` is computed once with
+    // `bytes_etag` rather than on every request.
+    pub fn with_etag(bytes: Vec<u8>, etag: Vec<u8>) -> Box<HTTPResponseWriter<W> + 'a> {
+        box BytesResponseWriter{bytes: bytes, etag: Some(etag)}
+    }
+}
+
+
+// A strong entity tag from the 64-bit FNV-1a hash of `bytes`.
+pub fn bytes_etag(bytes: &[u8]) -> Vec<u8> {
+    let mut hash = 0xcbf29ce484222325u64;
+    for b in bytes.iter() {
+        hash = (hash ^ *b as u64) * 0x100000001b3u64;
+    }
+    format!("\"{:016x}\"", hash).into_bytes()
+}
+
+
+// Value for a `Content-MD5` header of a response with `body`, for clients
+// checking the integrity of what they downloaded.
+pub fn content_md5(body: &[u8]) -> Vec<u8> {
+    base64_encode(md5::digest(body).as_slice())
+}
+
+
+fn base64_encode(bytes: &[u8]) -> Vec<u8> {
+    let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = Vec::with_capacity((bytes.len() + 2) / 3 * 4);
+    for group in bytes.chunks(3) {
+        let n = group.iter().enumerate()
+            .fold(0u32, |n, (i, b)| n | *b as u32 << (16 - 8 * i));
+        for i in range(0u, 4) {
+            if i <= group.len() {
+                encoded.push(alphabet[(n >> (18 - 6 * i) & 63) as uint]);
+            } else {
+                encoded.push(b'=');
+            }
+        }
+    }
+    encoded
+}
+
+
+// Weak comparison of `etag` against an `If-None-Match` value.
+fn etag_matches(if_none_match: &[u8], etag: &[u8]) -> bool {
+    let strip_weak = |tag: &[u8]| if tag.starts_with(b"W/") { tag.slice_from(2) } else { tag };
+    let etag = strip_weak(etag);
+    if_none_match.split(|b| *b == b',').any(|tag| {
+        let tag = trim_spaces(tag);
+        tag == b"*" || strip_weak(tag) == etag
+    })
+}
+
+
+// Responds to a GET for `bytes` with just the part asked for by its
+// Range header, if any. Only single ranges in bytes are served; a request
+// for several ranges gets the whole body.
+pub fn bytes_range_response<'req, W: Writer + Send + Sized>(request: &HTTPRequest, bytes: Vec<u8>)
+    -> (HTTPResponseCode, Box<HTTPHeaders>, Box<HTTPResponseWriter<W> + 'req>)
+{
+    let mut headers = box HashMap::new();
+    headers.insert(b"Accept-Ranges".to_vec(), b"bytes".to_vec());
+    let range = match request.method {
+        GET => byte_range(request.headers.get_header(b"Range"), bytes.len()),
+        _ => WholeBody,
+    };
+    match range {
+        WholeBody => {
+            let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(bytes);
+            (HTTP200, headers, writer)
+        },
+        Satisfiable(first, last) => {
+            headers.insert(b"Content-Range".to_vec(),
+                           format!("bytes {}-{}/{}", first, last, bytes.len()).into_bytes());
+            let writer: Box<HTTPResponseWriter<W>> =
+                BytesResponseWriter::<W>::new(bytes.slice(first, last + 1).to_vec());
+            (HTTP206, headers, writer)
+        },
+        Unsatisfiable => {
+            headers.insert(b"Content-Range".to_vec(), format!("bytes */{}", bytes.len()).into_bytes());
+            let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(vec![]);
+            (HTTP416, headers, writer)
+        },
+    }
+}
+
+
+#[deriving(Show, PartialEq)]
+enum ByteRange {
+    WholeBody,
+    // first and last byte, inclusive
+    Satisfiable(uint, uint),
+    Unsatisfiable,
+}
+
+
+// A Range header which cannot be parsed, or is in a unit other than bytes,
+// is ignored as if it was not there.
+fn byte_range(range: Option<&[u8]>, length: uint) -> ByteRange {
+    let spec = match range.map(trim_spaces) {
+        Some(value) if value.len() > 6 && eq_ignore_case(value.slice_to(6), b"bytes=") =>
+            trim_spaces(value.slice_from(6)),
+        _ => return WholeBody,
+    };
+    let dash = match spec.iter().position(|b| *b == b'-') {
+        Some(dash) if !spec.contains(&b',') => dash,
+        _ => return WholeBody,
+    };
+    let (first, last) = (spec.slice_to(dash), spec.slice_from(dash + 1));
+    match (parse_digits(first), parse_digits(last)) {
+        // the last `n` bytes
+        (None, Some(n)) if first.is_empty() =>
+            if n == 0 || length == 0 { Unsatisfiable } else { Satisfiable(length - min(n, length), length - 1) },
+        (Some(first), None) if last.is_empty() =>
+            if first >= length { Unsatisfiable } else { Satisfiable(first, length - 1) },
+        (Some(first), Some(last)) if first <= last =>
+            if first >= length { Unsatisfiable } else { Satisfiable(first, min(last, length - 1)) },
+        _ => WholeBody,
+    }
+}
+
+
+fn parse_digits(digits: &[u8]) -> Option<uint> {
+    if digits.is_empty() || !digits.iter().all(|b| b'0' <= *b && *b <= b'9') {
+        return None
+    }
+    from_utf8(digits).and_then(from_str::<uint>)
+}
+
+
+impl <W: Writer + Send + Sized>HTTPResponseWriter<W>
+    for BytesResponseWriter
+{
+    fn get_content_length(&self) -> Option<u64> {
+        Some(self.bytes.len() as u64)
+    }
+
+    fn get_content_type(&self) -> String {
+        "text/html".to_string()
+    }
+
+    fn write_data(&self, stream: &mut BufferedWriter<W>) -> IoResult<()> {
+        stream.write(self.bytes.as_slice())
+    }
+
+    fn should_autoflush(&self) -> bool {
+        false
+    }
+
+    fn get_etag(&self) -> Option<Vec<u8>> {
+        self.etag.clone()
+    }
+}
+
+
+pub struct TextResponseWriter {
+    text: String,
+}
+
+
+impl <'a, W: Writer + Send + Sized>TextResponseWriter {
+    pub fn new(text: String) -> Box<HTTPResponseWriter<W> + 'a> {
+        box TextResponseWriter{text: text}
+    }
+}
+
+
+impl <W: Writer + Send + Sized>HTTPResponseWriter<W>
+    for TextResponseWriter
+{
+    fn get_content_length(&self) -> Option<u64> {
+        Some(self.text.len() as u64)
+    }
+
+    fn get_content_type(&self) -> String {
+        "text/plain; charset=utf-8".to_string()
+    }
+
+    fn write_data(&self, stream: &mut BufferedWriter<W>) -> IoResult<()> {
+        stream.write_str(self.text.as_slice())
+    }
+
+    fn should_autoflush(&self) -> bool {
+        false
+    }
+}
+
+
+// A body produced elsewhere, e.g. by a task waiting on an upstream service,
+// and sent over as chunks which go out to the client as they arrive. The
+// body ends when the sender is dropped; as its length is unknown, so does
+// the connection. `write_data` blocks on the channel meanwhile, which under
+// the green scheduler parks only the connection's own task.
+pub struct ChannelResponseWriter {
+    receiver: Receiver<Vec<u8>>,
+    content_type: String,
+}
+
+
+impl <'a, W: Writer + Send + Sized>ChannelResponseWriter {
+    pub fn new(content_type: &str) -> (Sender<Vec<u8>>, Box<HTTPResponseWriter<W> + 'a>) {
+        let (sender, receiver) = channel();
+        let writer: Box<HTTPResponseWriter<W>> = box ChannelResponseWriter{
+            receiver: receiver,
+            content_type: content_type.to_string(),
+        };
+        (sender, writer)
+    }
+}
+
+
+impl <W: Writer + Send + Sized>HTTPResponseWriter<W>
+    for ChannelResponseWriter
+{
+    fn get_content_length(&self) -> Option<u64> {
+        None
+    }
+
+    fn get_content_type(&self) -> String {
+        self.content_type.clone()
+    }
+
+    fn is_streaming(&self) -> bool {
+        true
+    }
+
+    fn write_data(&self, stream: &mut BufferedWriter<W>) -> IoResult<()> {
+        loop {
+            match self.receiver.recv_opt() {
+                Ok(chunk) => {
+                    try!(stream.write(chunk.as_slice()));
+                    try!(stream.flush());
+                },
+                Err(()) => return Ok(()),
+            }
+        }
+    }
+}
+
+
+// Sends the bodies of several writers one after the other, e.g. a page
+// header, content and footer kept apart. The content type is the first
+// part's; the length is unknown if any part's is.
+pub struct ConcatResponseWriter<'a, W> {
+    parts: Vec<Box<HTTPResponseWriter<W> + 'a>>,
+}
+
+
+impl <'a, W: Writer + Send + Sized>ConcatResponseWriter<'a, W> {
+    pub fn new(parts: Vec<Box<HTTPResponseWriter<W> + 'a>>) -> Box<HTTPResponseWriter<W> + 'a> {
+        box ConcatResponseWriter{parts: parts}
+    }
+}
+
+
+impl <'a, W: Writer + Send + Sized>HTTPResponseWriter<W>
+    for ConcatResponseWriter<'a, W>
+{
+    fn get_content_length(&self) -> Option<u64> {
+        self.parts.iter().fold(Some(0), |total, part| match (total, part.get_content_length()) {
+            (Some(total), Some(length)) => Some(total + length),
+            _ => None,
+        })
+    }
+
+    fn get_content_type(&self) -> String {
+        match self.parts.as_slice().head() {
+            Some(part) => part.get_content_type(),
+            None => "text/html".to_string(),
+        }
+    }
+
+    fn is_streaming(&self) -> bool {
+        self.parts.iter().any(|part| part.is_streaming())
+    }
+
+    fn should_autoflush(&self) -> bool {
+        self.parts.iter().any(|part| part.should_autoflush())
+    }
+
+    fn write_data(&self, stream: &mut BufferedWriter<W>) -> IoResult<()> {
+        for part in self.parts.iter() {
+            try!(part.write_data(stream));
+        }
+        Ok(())
+    }
+}
+
+
+// Streams `length` bytes of a file from disk, starting at `offset`, opening
+// it only once the body is due.
+struct FileResponseWriter {
+    path: Path,
+    offset: u64,
+    length: u64,
+    content_type: String,
+}
+
+
+impl <W: Writer + Send + Sized>HTTPResponseWriter<W>
+    for FileResponseWriter
+{
+    fn get_content_length(&self) -> Option<u64> {
+        Some(self.length)
+    }
+
+    fn get_content_type(&self) -> String {
+        self.content_type.clone()
+    }
+
+    fn write_data(&self, stream: &mut BufferedWriter<W>) -> IoResult<()> {
+        let mut file = try!(File::open(&self.path));
+        if self.offset > 0 {
+            try!(file.seek(self.offset as i64, SeekSet));
+        }
+        let mut buf = [0u8, ..8192];
+        let mut remaining = self.length;
+        // never more than announced, should the file have grown meanwhile
+        while remaining > 0 {
+            let wanted = min(remaining, buf.len() as u64) as uint;
+            let count = match file.read(buf.slice_to_mut(wanted)) {
+                Ok(count) => count,
+                // the file shrank; the client was promised more, so the
+                // connection must not be reused
+                Err(ref e) if e.kind == EndOfFile => return Err(IoError{
+                    kind: OtherIoError,
+                    desc: "file shorter than its announced length",
+                    detail: Some(format!("{} bytes missing", remaining)),
+                }),
+                Err(e) => return Err(e),
+            };
+            try!(stream.write(buf.slice_to(count)));
+            remaining -= count as u64;
+        }
+        Ok(())
+    }
+
+    fn should_autoflush(&self) -> bool {
+        false
+    }
+}
+
+
+// The file under `root` a request path such as "/docs/a%20b.txt" names,
+// or None for paths which would end up outside of it or cannot be a file
+// name: "..", encoded or not, going above `root`, NUL bytes, backslashes
+// and drive letters. Strip the query string first.
+pub fn safe_path_join(root: &Path, url_path: &[u8]) -> Option<Path> {
+    let decoded = match percent_decode(url_path) {
+        Some(decoded) => decoded,
+        None => return None,
+    };
+    let mut segments: Vec<&[u8]> = vec![];
+    for segment in decoded.as_slice().split(|b| *b == b'/') {
+        match segment {
+            b"" | b"." => {},
+            b".." => if segments.pop().is_none() {
+                return None
+            },
+            _ if segment.iter().any(|b| *b == 0 || *b == b'\\' || *b == b':') => return None,
+            _ => segments.push(segment),
+        }
+    }
+    let mut path = root.clone();
+    for segment in segments.iter() {
+        path.push(*segment);
+    }
+    Some(path)
+}
+
+
+// Decodes %XX escapes, failing on malformed ones.
+fn percent_decode(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 2 >= bytes.len() {
+                return None
+            }
+            match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                (Some(high), Some(low)) => decoded.push(high << 4 | low),
+                _ => return None,
+            }
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0' ... b'9' => Some(b - b'0'),
+        b'a' ... b'f' => Some(b - b'a' + 10),
+        b'A' ... b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+
+// Response making the browser download the file at `path`, saving it as
+// `filename`. A missing file gets a 404. A single byte range asked for by a
+// GET is served alone, so that interrupted downloads can be resumed.
+pub fn download_response<'req, W: Writer + Send + Sized>(request: &HTTPRequest, path: &Path, filename: &str)
+    -> IoResult<(HTTPResponseCode, Box<HTTPHeaders>, Box<HTTPResponseWriter<W> + 'req>)>
+{
+    let length = match path.stat() {
+        Ok(ref stat) if stat.kind == TypeFile => stat.size,
+        Ok(_) => return Ok(not_found_response()),
+        Err(ref e) if e.kind == FileNotFound => return Ok(not_found_response()),
+        Err(e) => return Err(e),
+    };
+    let mut headers = box HashMap::new();
+    headers.insert(b"Content-Disposition".to_vec(), attachment_disposition(filename));
+    headers.insert(b"Accept-Ranges".to_vec(), b"bytes".to_vec());
+    let range = match request.method {
+        GET => byte_range(request.headers.get_header(b"Range"), length as uint),
+        _ => WholeBody,
+    };
+    let (code, offset, count) = match range {
+        WholeBody => (HTTP200, 0, length),
+        Satisfiable(first, last) => {
+            headers.insert(b"Content-Range".to_vec(),
+                           format!("bytes {}-{}/{}", first, last, length).into_bytes());
+            (HTTP206, first as u64, (last - first + 1) as u64)
+        },
+        Unsatisfiable => {
+            headers.insert(b"Content-Range".to_vec(), format!("bytes */{}", length).into_bytes());
+            let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(vec![]);
+            return Ok((HTTP416, headers, writer))
+        },
+    };
+    let writer: Box<HTTPResponseWriter<W>> = box FileResponseWriter{
+        path: path.clone(),
+        offset: offset,
+        length: count,
+        content_type: "application/octet-stream".to_string(),
+    };
+    Ok((code, headers, writer))
+}
+
+
+// A `Warning` header value, e.g. for `110` ("Response is Stale") or `199`
+// ("Miscellaneous Warning"). Several warnings go into one header with
+// `append_header`.
+pub fn warning_value(code: u16, agent: &str, text: &str) -> Vec<u8> {
+    let mut value = format!("{:03} {} \"", code, agent).into_bytes();
+    for b in text.as_bytes().iter() {
+        if *b == b'"' || *b == b'\\' {
+            value.push(b'\\');
+        }
+        value.push(*b);
+    }
+    value.push(b'"');
+    value
+}
+
+
+// An `Age` header value for a response stored in a cache at `stored`: the
+// whole seconds it has been there, never negative.
+pub fn age_value(stored: Timespec, clock: &Clock) -> Vec<u8> {
+    max(0, (clock.now() - stored).num_seconds()).to_string().into_bytes()
+}
+
+
+// Picks the best of the `available` locales for an `Accept-Language` header
+// value. A language range also matches the more specific locales it is a
+// prefix of ("en" matches "en-US"), and a locale its primary language
+// ("en-GB" matches "en"). Ranges with a malformed q-value count as q=1.
+pub fn negotiate_language<'a>(accept_language: &[u8], available: &[&'a str])
+                              -> Option<&'a str>
+{
+    let mut ranges: Vec<(&[u8], f64)> = accept_language.split(|b| *b == b',')
+        .filter_map(|item| {
+            let mut params = item.split(|b| *b == b';');
+            let range = trim_spaces(params.next().unwrap_or(b""));
+            if range.is_empty() {
+                return None
+            }
+            let q = params.filter_map(|param| {
+                let param = trim_spaces(param);
+                if param.starts_with(b"q=") {
+                    Some(from_utf8(param.slice_from(2)).and_then(from_str::<f64>).unwrap_or(1.0))
+                } else {
+                    None
+                }
+            }).next().unwrap_or(1.0);
+            Some((range, q))
+        })
+        .filter(|&(_, q)| q > 0.0)
+        .collect();
+    // stable, so equally preferred ranges keep the client's order
+    ranges.sort_by(|&(_, a), &(_, b)| b.partial_cmp(&a).unwrap_or(Equal));
+
+    for &(range, _) in ranges.iter() {
+        if range == b"*" {
+            return available.head().map(|locale| *locale)
+        }
+        let primary = match range.iter().position(|b| *b == b'-') {
+            Some(i) => range.slice_to(i),
+            None => range,
+        };
+        let found = available.iter()
+            .find(|locale| eq_ignore_case(locale.as_bytes(), range))
+            .or_else(|| available.iter().find(|locale| {
+                let locale = locale.as_bytes();
+                locale.len() > range.len() && locale[range.len()] == b'-'
+                    && eq_ignore_case(locale.slice_to(range.len()), range)
+            }))
+            .or_else(|| available.iter().find(|locale| eq_ignore_case(locale.as_bytes(), primary)));
+        match found {
+            Some(locale) => return Some(*locale),
+            None => {},
+        }
+    }
+    None
+}
+
+
+// Value for a `Content-Disposition` header which makes browsers download
+// the response as `filename`. Non-ASCII names are additionally sent in the
+// RFC 5987 `filename*` form, with an underscored ASCII fallback.
+pub fn attachment_disposition(filename: &str) -> Vec<u8> {
+    let mut value = b"attachment; filename=\"".to_vec();
+    let mut ascii = true;
+    for c in filename.chars() {
+        match c {
+            '"' | '\\' => {
+                value.push(b'\\');
+                value.push(c as u8);
+            },
+            ' ' ... '~' => value.push(c as u8),
+            _ => {
+                ascii = ascii && (c as u32) < 0x80;
+                value.push(b'_');
+            },
+        }
+    }
+    value.push(b'"');
+
+    if !ascii {
+        value.push_all(b"; filename*=UTF-8''");
+        for b in filename.as_bytes().iter() {
+            match *b {
+                b'a' ... b'z' | b'A' ... b'Z' | b'0' ... b'9'
+                    | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.'
+                    | b'^' | b'_' | b'`' | b'|' | b'~' => value.push(*b),
+                _ => value.push_all(format!("%{:02X}", *b).as_bytes()),
+            }
+        }
+    }
+    value
+}
+
+
+// Error details are only shown to the client in debug mode.
+fn server_error_response<'req, W: Writer + Send + Sized>
+    (error: &IoError, config: &ServerConfig)
+    -> (HTTPResponseCode, Box<HTTPHeaders>, Box<HTTPResponseWriter<W> + 'req>)
+{
+    let mut page = b"<h1>500 Server Error</h1>".to_vec();
+    if config.debug {
+        page.push_all(b"<pre>");
+        page.push_all(html_escape(format!("{}", error).as_bytes()).as_slice());
+        page.push_all(b"</pre>");
+    }
+    let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(page);
+    (HTTP500, box HashMap::new(), writer)
+}
+
+
+fn html_escape(text: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(text.len());
+    for b in text.iter() {
+        match *b {
+            b'<' => escaped.push_all(b"&lt;"),
+            b'>' => escaped.push_all(b"&gt;"),
+            b'&' => escaped.push_all(b"&amp;"),
+            b'"' => escaped.push_all(b"&quot;"),
+            b'\'' => escaped.push_all(b"&#39;"),
+            _ => escaped.push(*b),
+        }
+    }
+    escaped
+}
+
+
+fn not_found_response<'req, W: Writer + Send + Sized>()
+    -> (HTTPResponseCode, Box<HTTPHeaders>, Box<HTTPResponseWriter<W> + 'req>)
+{
+    let writer: Box<HTTPResponseWriter<W>> =
+        BytesResponseWriter::<W>::new(b"<h1>404 Not Found</h1>".to_vec());
+    (HTTP404, box HashMap::new(), writer)
+}
+
+
+// Response telling the client to switch to one of the `upgrade` protocols,
+// e.g. "TLS/1.2, HTTP/1.1" or "websocket", and try again.
+pub fn upgrade_required_response<'req, W: Writer + Send + Sized>(upgrade: &str)
+    -> (HTTPResponseCode, Box<HTTPHeaders>, Box<HTTPResponseWriter<W> + 'req>)
+{
+    let mut headers = box HashMap::new();
+    headers.insert(b"Upgrade".to_vec(), upgrade.as_bytes().to_vec());
+    let writer: Box<HTTPResponseWriter<W>> =
+        BytesResponseWriter::<W>::new(b"<h1>426 Upgrade Required</h1>".to_vec());
+    (HTTP426, headers, writer)
+}
+
+
+// Response to a request which was taken on but will be processed later.
+pub fn accepted_response<'req, W: Writer + Send + Sized>(body: Vec<u8>)
+    -> (HTTPResponseCode, Box<HTTPHeaders>, Box<HTTPResponseWriter<W> + 'req>)
+{
+    let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(body);
+    (HTTP202, box HashMap::new(), writer)
+}
+
+
+pub fn no_content_response<'req, W: Writer + Send + Sized>()
+    -> (HTTPResponseCode, Box<HTTPHeaders>, Box<HTTPResponseWriter<W> + 'req>)
+{
+    let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(vec![]);
+    (HTTP204, box HashMap::new(), writer)
+}
+
+
+// Response to a request which created the resource now found at `location`.
+pub fn created_response<'req, W: Writer + Send + Sized>(location: &str, body: Vec<u8>)
+    -> (HTTPResponseCode, Box<HTTPHeaders>, Box<HTTPResponseWriter<W> + 'req>)
+{
+    let mut headers = box HashMap::new();
+    headers.insert(b"Location".to_vec(), location.as_bytes().to_vec());
+    let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(body);
+    (HTTP201, headers, writer)
+}
+
+
+pub struct Mount<T> {
+    prefix: Vec<u8>,
+    handler: T,
+}
+
+
+impl <T> Mount<T> {
+    pub fn new(prefix: &str, handler: T) -> Mount<T> {
+        let mut prefix = prefix.as_bytes().to_vec();
+        while prefix.last() == Some(&b'/') {
+            prefix.pop();
+        }
+        Mount{prefix: prefix, handler: handler}
+    }
+
+    // The request as the mounted handler sees it, if it is under the prefix.
+    fn strip_prefix(&self, request: &HTTPRequest) -> Option<HTTPRequest> {
+        let path = &request.path;
+        if !path.as_slice().starts_with(self.prefix.as_slice()) {
+            return None
+        }
+
+        // "/api" must not swallow "/apidocs", only "/api", "/api/..." and "/api?..."
+        let rest = path.slice_from(self.prefix.len());
+        let inner_path = match rest.head() {
+            None => b"/".to_vec(),
+            Some(&b'/') => rest.to_vec(),
+            Some(&b'?') => {
+                let mut p = b"/".to_vec();
+                p.push_all(rest);
+                p
+            },
+            Some(_) => return None,
+        };
+
+        let mut inner_request = request.clone();
+        inner_request.path = inner_path;
+        inner_request.mount_prefix.push_all(self.prefix.as_slice());
+        Some(inner_request)
+    }
+}
+
+
+impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized,
+      T: HTTPRequestHandler<'req, R, W>>
+    HTTPRequestHandler<'req, R, W>
+    for Mount<T>
+{
+    fn handle(
+        &self,
+        request: &HTTPRequest,
+        body: &mut BodyReader<R>)
+        -> IoResult<Option<(HTTPResponseCode,
+                            Box<HTTPHeaders>,
+                            Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        match self.strip_prefix(request) {
+            Some(inner_request) => self.handler.handle(&inner_request, body),
+            None => Ok(Some(not_found_response())),
+        }
+    }
+
+    fn take_over(
+        &self,
+        request: &HTTPRequest,
+        reader: &mut BufferedReader<R>,
+        writer: &mut BufferedWriter<W>)
+        -> IoResult<bool>
+    {
+        match self.strip_prefix(request) {
+            Some(inner_request) => self.handler.take_over(&inner_request, reader, writer),
+            None => Ok(false),
+        }
+    }
+
+    fn check_continue(&self, request: &HTTPRequest)
+                      -> IoResult<Option<(HTTPResponseCode,
+                                          Box<HTTPHeaders>,
+                                          Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        match self.strip_prefix(request) {
+            Some(inner_request) => self.handler.check_continue(&inner_request),
+            None => Ok(None),
+        }
+    }
+}
+
+
+// Stores the files of `multipart/form-data` POST requests in `directory`
+// under generated names, answering with a plain text list of what was
+// stored. Uploads of more than `max_size` bytes in total get a 413, up front
+// if the client announced the size and waits for 100 Continue. Whatever was
+// stored for an upload which failed is removed again.
+pub struct UploadHandler {
+    directory: Path,
+    max_size: u64,
+}
+
+
+struct StoredUpload {
+    filename: Vec<u8>,
+    path: Path,
+    size: u64,
+}
+
+
+impl UploadHandler {
+    pub fn new(directory: Path, max_size: u64) -> UploadHandler {
+        UploadHandler{directory: directory, max_size: max_size}
+    }
+
+    // Tells whether all of the parts fitted into `max_size`.
+    fn store_parts<P: Reader>(&self, parts: &mut MultipartReader<P>,
+                              stored: &mut Vec<StoredUpload>)
+                              -> IoResult<bool>
+    {
+        let mut total = 0u64;
+        let mut buf = [0u8, ..8192];
+        loop {
+            let headers = match try!(parts.next_part()) {
+                Some(headers) => headers,
+                None => return Ok(true),
+            };
+            // plain form fields are skipped
+            let filename = match headers.get_header(b"Content-Disposition") {
+                Some(disposition) => match content_type_param(disposition, b"filename") {
+                    Some(filename) => filename,
+                    None => continue,
+                },
+                None => continue,
+            };
+
+            let path = self.directory.join(format!("upload-{:016x}", random::<u64>()));
+            let mut file = try!(File::create(&path));
+            stored.push(StoredUpload{filename: filename, path: path, size: 0});
+            loop {
+                let count = match parts.read(buf) {
+                    Ok(count) => count,
+                    Err(ref e) if e.kind == EndOfFile => break,
+                    Err(e) => return Err(e),
+                };
+                total += count as u64;
+                if total > self.max_size {
+                    return Ok(false)
+                }
+                try!(file.write(buf.slice_to(count)));
+                match stored.last_mut() {
+                    Some(upload) => upload.size += count as u64,
+                    None => {},
+                }
+            }
+        }
+    }
+}
+
+
+// Closes the connection rather than reading the rest of the upload first.
+fn too_large_response<'req, W: Writer + Send + Sized>()
+    -> (HTTPResponseCode, Box<HTTPHeaders>, Box<HTTPResponseWriter<W> + 'req>)
+{
+    let mut headers = box HashMap::new();
+    headers.insert(b"Connection".to_vec(), b"close".to_vec());
+    let writer: Box<HTTPResponseWriter<W>> =
+        BytesResponseWriter::<W>::new(b"<h1>413 Request Entity Too Large</h1>".to_vec());
+    (HTTP413, headers, writer)
+}
+
+
+impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized>
+    HTTPRequestHandler<'req, R, W>
+    for UploadHandler
+{
+    fn handle(
+        &self,
+        request: &HTTPRequest,
+        body: &mut BodyReader<R>)
+        -> IoResult<Option<(HTTPResponseCode,
+                            Box<HTTPHeaders>,
+                            Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        if request.method != POST {
+            let mut headers = box HashMap::new();
+            headers.insert(b"Allow".to_vec(), b"POST".to_vec());
+            let writer: Box<HTTPResponseWriter<W>> =
+                BytesResponseWriter::<W>::new(b"<h1>405 Method Not Allowed</h1>".to_vec());
+            return Ok(Some((HTTP405, headers, writer)))
+        }
+        let boundary = match request.headers.content_type().and_then(multipart_boundary) {
+            Some(boundary) => boundary,
+            None => return Ok(None),
+        };
+
+        let mut stored = vec![];
+        let result = self.store_parts(
+            &mut MultipartReader::new(body.by_ref(), boundary.as_slice()), &mut stored);
+        match result {
+            Ok(true) => {},
+            _ => for upload in stored.iter() {
+                match unlink(&upload.path) {
+                    Ok(()) => {},
+                    Err(e) => error!("Could not remove {}: {}", upload.path.display(), e),
+                }
+            },
+        }
+        match result {
+            Ok(true) => {},
+            Ok(false) => return Ok(Some(too_large_response())),
+            Err(ref e) if e.kind == InvalidInput => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mut summary = String::new();
+        for upload in stored.iter() {
+            summary.push_str(format!("{} {} {}\n",
+                                     // the generated name only, not where the server keeps it
+                                     upload.path.filename_display(),
+                                     upload.size,
+                                     String::from_utf8_lossy(upload.filename.as_slice())).as_slice());
+        }
+        let writer: Box<HTTPResponseWriter<W>> = TextResponseWriter::<W>::new(summary);
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    fn check_continue(&self, request: &HTTPRequest)
+                      -> IoResult<Option<(HTTPResponseCode,
+                                          Box<HTTPHeaders>,
+                                          Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        match request.headers.content_length() {
+            Some(length) if length > self.max_size => Ok(Some(too_large_response())),
+            _ => Ok(None),
+        }
+    }
+}
+
+
+// Answers `/favicon.ico` in front of another handler, so that browsers stop
+// filling the log with 404s for it. Without an icon the answer is a 204.
+pub struct FaviconHandler<T> {
+    icon: Option<Vec<u8>>,
+    handler: T,
+}
+
+
+impl <T> FaviconHandler<T> {
+    pub fn new(handler: T) -> FaviconHandler<T> {
+        FaviconHandler{icon: None, handler: handler}
+    }
+
+    pub fn with_icon(icon: Vec<u8>, handler: T) -> FaviconHandler<T> {
+        FaviconHandler{icon: Some(icon), handler: handler}
+    }
+}
+
+
+impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized,
+      T: HTTPRequestHandler<'req, R, W>>
+    HTTPRequestHandler<'req, R, W>
+    for FaviconHandler<T>
+{
+    fn handle(
+        &self,
+        request: &HTTPRequest,
+        body: &mut BodyReader<R>)
+        -> IoResult<Option<(HTTPResponseCode,
+                            Box<HTTPHeaders>,
+                            Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        let (path, _) = split_query(request.path.as_slice());
+        if path != b"/favicon.ico" || (request.method != GET && request.method != HEAD) {
+            return self.handler.handle(request, body)
+        }
+
+        match self.icon {
+            Some(ref icon) => {
+                let mut headers = box HashMap::new();
+                headers.insert(b"Content-Type".to_vec(), b"image/x-icon".to_vec());
+                headers.insert(b"Cache-Control".to_vec(), b"public, max-age=86400".to_vec());
+                let writer: Box<HTTPResponseWriter<W>> =
+                    BytesResponseWriter::<W>::new(icon.clone());
+                Ok(Some((HTTP200, headers, writer)))
+            },
+            None => Ok(Some(no_content_response())),
+        }
+    }
+
+    fn take_over(
+        &self,
+        request: &HTTPRequest,
+        reader: &mut BufferedReader<R>,
+        writer: &mut BufferedWriter<W>)
+        -> IoResult<bool>
+    {
+        self.handler.take_over(request, reader, writer)
+    }
+
+    fn check_continue(&self, request: &HTTPRequest)
+                      -> IoResult<Option<(HTTPResponseCode,
+                                          Box<HTTPHeaders>,
+                                          Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        self.handler.check_continue(request)
+    }
+}
+
+
+// Rewrites request paths before the inner handler sees them, e.g. to drop
+// a locale prefix. `rewrite` returning `None` leaves the path as it is.
+pub struct PathRewrite<T> {
+    rewrite: fn(&[u8]) -> Option<Vec<u8>>,
+    handler: T,
+}
+
+
+impl <T> PathRewrite<T> {
+    pub fn new(rewrite: fn(&[u8]) -> Option<Vec<u8>>, handler: T) -> PathRewrite<T> {
+        PathRewrite{rewrite: rewrite, handler: handler}
+    }
+
+    fn rewrite_request(&self, request: &HTTPRequest) -> Option<HTTPRequest> {
+        (self.rewrite)(request.path.as_slice()).map(|path| {
+            let mut rewritten = request.clone();
+            rewritten.path = path;
+            rewritten
+        })
+    }
+}
+
+
+impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized,
+      T: HTTPRequestHandler<'req, R, W>>
+    HTTPRequestHandler<'req, R, W>
+    for PathRewrite<T>
+{
+    fn handle(
+        &self,
+        request: &HTTPRequest,
+        body: &mut BodyReader<R>)
+        -> IoResult<Option<(HTTPResponseCode,
+                            Box<HTTPHeaders>,
+                            Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        match self.rewrite_request(request) {
+            Some(rewritten) => self.handler.handle(&rewritten, body),
+            None => self.handler.handle(request, body),
+        }
+    }
+
+    fn take_over(
+        &self,
+        request: &HTTPRequest,
+        reader: &mut BufferedReader<R>,
+        writer: &mut BufferedWriter<W>)
+        -> IoResult<bool>
+    {
+        match self.rewrite_request(request) {
+            Some(rewritten) => self.handler.take_over(&rewritten, reader, writer),
+            None => self.handler.take_over(request, reader, writer),
+        }
+    }
+
+    fn check_continue(&self, request: &HTTPRequest)
+                      -> IoResult<Option<(HTTPResponseCode,
+                                          Box<HTTPHeaders>,
+                                          Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        match self.rewrite_request(request) {
+            Some(rewritten) => self.handler.check_continue(&rewritten),
+            None => self.handler.check_continue(request),
+        }
+    }
+}
+
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum TrailingSlash {
+    // "/path" and "/path/" are unrelated routes
+    ExactSlash,
+    // "/path/" redirects to "/path" when only the latter is routed
+    StripSlash,
+    // "/path" redirects to "/path/" when only the latter is routed
+    AppendSlash,
+}
+
+
+struct Route<'req, R, W> {
+    // None for any host
+    host: Option<Vec<u8>>,
+    method: HTTPMethod,
+    path: Vec<u8>,
+    handler: Box<HTTPRequestHandler<'req, R, W> + Send + Sync>,
+}
+
+
+impl <'req, R, W> Route<'req, R, W> {
+    fn serves_host(&self, host: Option<&[u8]>) -> bool {
+        match (self.host.as_ref(), host) {
+            (None, _) => true,
+            (Some(route_host), Some(host)) => eq_ignore_case(route_host.as_slice(), host),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+
+// Dispatches requests by method and exact path. The query string plays no
+// part in matching.
+pub struct Router<'req, R, W> {
+    routes: Vec<Route<'req, R, W>>,
+    pub trailing_slash: TrailingSlash,
+    // Lists the routes as plain text at GET /__routes, for debugging. Not
+    // something to expose in production.
+    pub list_routes: bool,
+    // The origin allowed to make cross-origin requests, "*" for any. Without
+    // one, preflight requests get a plain OPTIONS response, which browsers
+    // take as a refusal.
+    pub cors_origin: Option<String>,
+}
+
+
+impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized> Router<'req, R, W> {
+    pub fn new() -> Router<'req, R, W> {
+        Router{routes: vec![], trailing_slash: ExactSlash, list_routes: false, cors_origin: None}
+    }
+
+    pub fn add_route(&mut self,
+                     method: HTTPMethod,
+                     path: &str,
+                     handler: Box<HTTPRequestHandler<'req, R, W> + Send + Sync>)
+    {
+        self.add_host_route(None, method, path, handler)
+    }
+
+    // Like `add_route`, but for requests to `host` only, given without a
+    // port, when not None. Routes for the request's host win over those
+    // for any host.
+    pub fn add_host_route(&mut self,
+                          host: Option<&str>,
+                          method: HTTPMethod,
+                          path: &str,
+                          handler: Box<HTTPRequestHandler<'req, R, W> + Send + Sync>)
+    {
+        self.routes.push(Route{
+            host: host.map(|host| host.as_bytes().to_vec()),
+            method: method,
+            path: path.as_bytes().to_vec(),
+            handler: handler,
+        });
+    }
+
+    fn find_route(&self, host: Option<&[u8]>, method: HTTPMethod, path: &[u8])
+                  -> Option<&Route<'req, R, W>>
+    {
+        let matching = self.routes.iter().filter(|route| {
+            route.method == method && route.path.as_slice() == path && route.serves_host(host)
+        });
+        let mut fallback = None;
+        for route in matching {
+            if route.host.is_some() {
+                return Some(route)
+            }
+            if fallback.is_none() {
+                fallback = Some(route);
+            }
+        }
+        fallback
+    }
+
+    fn has_path(&self, host: Option<&[u8]>, path: &[u8]) -> bool {
+        self.routes.iter().any(|route| route.path.as_slice() == path && route.serves_host(host))
+    }
+
+    fn slash_redirect(&self, host: Option<&[u8]>, path: &[u8]) -> Option<Vec<u8>> {
+        let alternative = match self.trailing_slash {
+            StripSlash if path.len() > 1 && path.ends_with(b"/") =>
+                path.slice_to(path.len() - 1).to_vec(),
+            AppendSlash if !path.ends_with(b"/") => {
+                let mut p = path.to_vec();
+                p.push(b'/');
+                p
+            },
+            _ => return None,
+        };
+        if self.has_path(host, alternative.as_slice()) { Some(alternative) } else { None }
+    }
+
+    // The Access-Control-Allow-Origin value for a request from `origin`, if
+    // that origin is allowed.
+    fn allowed_origin(&self, origin: Option<&[u8]>) -> Option<Vec<u8>> {
+        match (self.cors_origin.as_ref(), origin) {
+            (Some(allowed), Some(_)) if allowed.as_slice() == "*" => Some(b"*".to_vec()),
+            (Some(allowed), Some(origin)) if allowed.as_bytes() == origin => Some(origin.to_vec()),
+            _ => None,
+        }
+    }
+}
+
+
+impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized>
+    HTTPRequestHandler<'req, R, W>
+    for Router<'req, R, W>
+{
+    fn handle(
+        &self,
+        request: &HTTPRequest,
+        body: &mut BodyReader<R>)
+        -> IoResult<Option<(HTTPResponseCode,
+                            Box<HTTPHeaders>,
+                            Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        let (path, query) = split_query(request.path.as_slice());
+        let host = request.headers.host().map(strip_port);
+
+        match self.find_route(host, request.method, path) {
+            Some(route) => return route.handler.handle(request, body),
+            None => {},
+        }
+
+        if self.list_routes && request.method == GET && path == b"/__routes" {
+            let listing: Vec<String> = self.routes.iter()
+                .filter(|route| route.serves_host(host))
+                .map(|route| format!("{} {}{}\n", String::from_utf8_lossy(route.method.as_bytes()),
+                                     String::from_utf8_lossy(route.host.as_ref().map_or(b"", |h| h.as_slice())),
+                                     String::from_utf8_lossy(route.path.as_slice())))
+                .collect();
+            let listing = listing.concat();
+            let writer: Box<HTTPResponseWriter<W>> = TextResponseWriter::<W>::new(listing);
+            return Ok(Some((HTTP200, box HashMap::new(), writer)))
+        }
+
+        if self.has_path(host, path) {
+            // a method may be routed both for this host and for any host
+            let mut methods: Vec<HTTPMethod> = vec![];
+            for route in self.routes.iter() {
+                if route.path.as_slice() == path && route.serves_host(host) && !methods.contains(&route.method) {
+                    methods.push(route.method);
+                }
+            }
+            if !methods.contains(&OPTIONS) {
+                methods.push(OPTIONS);
+            }
+            let mut allowed = vec![];
+            for (i, method) in methods.iter().enumerate() {
+                if i > 0 {
+                    allowed.push_all(b", ");
+                }
+                allowed.push_all(method.as_bytes());
+            }
+            let mut headers = box HashMap::new();
+
+            // answered for every routed path, doubling as a CORS preflight
+            // response when the browser asks for one
+            if request.method == OPTIONS {
+                if request.headers.get_header(b"Access-Control-Request-Method").is_some() {
+                    match self.allowed_origin(request.headers.get_header(b"Origin")) {
+                        Some(origin) => {
+                            if origin.as_slice() != b"*" {
+                                headers.insert(b"Vary".to_vec(), b"Origin".to_vec());
+                            }
+                            headers.insert(b"Access-Control-Allow-Origin".to_vec(), origin);
+                            headers.insert(b"Access-Control-Allow-Methods".to_vec(), allowed.clone());
+                            match request.headers.get_header(b"Access-Control-Request-Headers") {
+                                Some(requested) => {
+                                    headers.insert(b"Access-Control-Allow-Headers".to_vec(), requested.to_vec());
+                                },
+                                None => {},
+                            }
+                        },
+                        None => {},
+                    }
+                }
+                headers.insert(b"Allow".to_vec(), allowed);
+                let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(vec![]);
+                return Ok(Some((HTTP200, headers, writer)))
+            }
+
+            headers.insert(b"Allow".to_vec(), allowed);
+            let writer: Box<HTTPResponseWriter<W>> =
+                BytesResponseWriter::<W>::new(b"<h1>405 Method Not Allowed</h1>".to_vec());
+            return Ok(Some((HTTP405, headers, writer)))
+        }
+
+        match self.slash_redirect(host, path) {
+            Some(alternative) => {
+                let mut location = request.mount_prefix.clone();
+                location.push_all(alternative.as_slice());
+                location.push_all(query);
+                let mut headers = box HashMap::new();
+                headers.insert(b"Location".to_vec(), location);
+                let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(vec![]);
+                Ok(Some((HTTP301, headers, writer)))
+            },
+            None => Ok(Some(not_found_response())),
+        }
+    }
+
+    fn take_over(
+        &self,
+        request: &HTTPRequest,
+        reader: &mut BufferedReader<R>,
+        writer: &mut BufferedWriter<W>)
+        -> IoResult<bool>
+    {
+        let (path, _) = split_query(request.path.as_slice());
+        let host = request.headers.host().map(strip_port);
+        match self.find_route(host, request.method, path) {
+            Some(route) => route.handler.take_over(request, reader, writer),
+            None => Ok(false),
+        }
+    }
+
+    fn check_continue(&self, request: &HTTPRequest)
+                      -> IoResult<Option<(HTTPResponseCode,
+                                          Box<HTTPHeaders>,
+                                          Box<HTTPResponseWriter<W> + 'req>)>>
+    {
+        let (path, _) = split_query(request.path.as_slice());
+        let host = request.headers.host().map(strip_port);
+        match self.find_route(host, request.method, path) {
+            Some(route) => route.handler.check_continue(request),
+            None => Ok(None),
+        }
+    }
+}
+
+
+// "example.com:8080" -> "example.com", "[::1]:8080" -> "[::1]"
+fn strip_port(host: &[u8]) -> &[u8] {
+    let end = if host.starts_with(b"[") {
+        host.iter().position(|b| *b == b']').map(|i| i + 1)
+    } else {
+        host.iter().position(|b| *b == b':')
+    };
+    host.slice_to(end.unwrap_or(host.len()))
+}
+
+
+// Splits a request target into its path and its query, the latter
+// including the leading "?".
+fn split_query(target: &[u8]) -> (&[u8], &[u8]) {
+    match target.iter().position(|b| *b == b'?') {
+        Some(i) => (target.slice_to(i), target.slice_from(i)),
+        None => (target, b""),
+    }
+}
+
+
+fn start_http_response<W: Writer>
+    (writer: &mut BufferedWriter<W>,
+     version: HTTPVersion,
+     response_code: HTTPResponseCode,
+     reason_phrase: Option<&str>,
+     headers: &HTTPHeaders,
+     flush: bool)
+     -> IoResult<()>
+{
+    try!(writer.write_str(match version {
+        HTTP10 => "HTTP/1.0 ",
+        HTTP11 => "HTTP/1.1 ",
+    }));
+    match reason_phrase {
+        Some(phrase) => try!(write!(writer, "{} {}", response_code as int, phrase)),
+        None => try!(writer.write_str(default_status_line(response_code))),
+    }
+    try!(writer.write_str("\r\n"));
+
+    for (key, value) in headers.iter() {
+        try!(writer.write(canonical_header_name(key.as_slice()).as_slice()));
+        try!(writer.write_str(": "));
+        try!(writer.write(value.as_slice()));
+        try!(writer.write_str("\r\n"));
+    }
+
+    try!(writer.write_str("\r\n"));
+    if flush {
+        try!(writer.flush());
+    }
+    Ok(())
+}
+
+
+// Header names are case-insensitive, but some clients only look for them
+// written the usual way, e.g. "Content-Type" rather than "content-type".
+static IRREGULAR_HEADER_NAMES: [&'static [u8], ..6] = [
+    b"ETag", b"WWW-Authenticate", b"Content-MD5", b"TE", b"DNT", b"X-XSS-Protection",
+];
+
+
+fn canonical_header_name(name: &[u8]) -> Vec<u8> {
+    match IRREGULAR_HEADER_NAMES.iter().find(|known| eq_ignore_case(**known, name)) {
+        Some(known) => return known.to_vec(),
+        None => {},
+    }
+    let mut start = true;
+    name.iter().map(|&b| {
+        let b = if start { ascii_upper(b) } else { ascii_lower(b) };
+        start = b == b'-';
+        b
+    }).collect()
+}
+
+
+fn default_status_line(response_code: HTTPResponseCode) -> &'static str {
+    match response_code {
+        HTTP200 => "200 Ok",
+        HTTP201 => "201 Created",
+        HTTP202 => "202 Accepted",
+        HTTP204 => "204 No Content",
+        HTTP206 => "206 Partial Content",
+        HTTP301 => "301 Moved Permanently",
+        HTTP302 => "302 Found",
+        HTTP304 => "304 Not Modified",
+        HTTP400 => "400 Bad Request",
+        HTTP401 => "401 Unauthorized",
+        HTTP403 => "403 Forbidden",
+        HTTP404 => "404 Not Found",
+        HTTP405 => "405 Method Not Allowed",
+        HTTP413 => "413 Request Entity Too Large",
+        HTTP414 => "414 Request-URI Too Long",
+        HTTP415 => "415 Unsupported Media Type",
+        HTTP416 => "416 Requested Range Not Satisfiable",
+        HTTP426 => "426 Upgrade Required",
+        HTTP500 => "500 Server Error",
+        HTTP501 => "501 Not Implemented",
+        HTTP502 => "502 Bad Gateway",
+        HTTP505 => "505 HTTP Version Not Supported",
+        // stands for no response at all, so should never get here
+        HTTPERROR => "500 Server Error",
+    }
+}
+
+
+// Runs `handle_http` for one accepted connection. A failing handler or
+// response writer only takes down its own task; the client still gets a
+// 500 if nothing has been sent to it yet.
+fn serve_connection
+    <'req, S: Reader + Writer + ConnectionTimeouts + Clone + Send,
+     T: HTTPRequestHandler<'req, S, S> + Send + Sync + Sized>
+    (handler: Arc<T>, config: Arc<ServerConfig>, stream: S)
+{
+    LIVE_CONNECTIONS.fetch_add(1, SeqCst);
+    let headers_sent = Arc::new(AtomicBool::new(false));
+    let task_headers_sent = headers_sent.clone();
+    let task_stream = stream.clone();
+
+    let result = try(proc() {
+        let reader = BufferedReader::new(task_stream.clone());
+        let writer = BufferedWriter::new(task_stream);
+        handle_http(&*handler, &*config, &*task_headers_sent, reader, writer)
+    });
+
+    match result {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => info!("Connection closed: {}", e),
+        Err(cause) => {
+            let message = match cause.downcast_ref::<&'static str>() {
+                Some(s) => s.to_string(),
+                None => match cause.downcast_ref::<String>() {
+                    Some(s) => s.clone(),
+                    None => "Box<Any>".to_string(),
+                },
+            };
+            error!("Request handler panicked: {}", message);
+
+            if !headers_sent.load(SeqCst) {
+                let mut writer = BufferedWriter::new(stream);
+                let mut headers = HashMap::new();
+                headers.insert(b"Content-Length".to_vec(), b"0".to_vec());
+                let _ = start_http_response(&mut writer, HTTP10, HTTP500, None, &headers, true);
+            }
+        },
+    }
+    LIVE_CONNECTIONS.fetch_sub(1, SeqCst);
+}
+
+
+// Forward proxy support: `CONNECT host:port` opens a TCP connection to the
+// target and then relays bytes both ways until either side closes.
+pub struct ConnectHandler;
+
+
+impl <'req> HTTPRequestHandler<'req, TcpStream, TcpStream> for ConnectHandler {
+    // Only reached for requests `take_over` turned down.
+    fn handle(
+        &self,
+        request: &HTTPRequest,
+        body: &mut BodyReader<TcpStream>)
+        -> IoResult<Option<(HTTPResponseCode,
+                            Box<HTTPHeaders>,
+                            Box<HTTPResponseWriter<TcpStream> + 'req>)>>
+    {
+        let writer: Box<HTTPResponseWriter<TcpStream>> =
+            BytesResponseWriter::<TcpStream>::new(b"<h1>400 Bad Request</h1>".to_vec());
+        Ok(Some((HTTP400, box HashMap::new(), writer)))
+    }
+
+    fn take_over(
+        &self,
+        request: &HTTPRequest,
+        reader: &mut BufferedReader<TcpStream>,
+        writer: &mut BufferedWriter<TcpStream>)
+        -> IoResult<bool>
+    {
+        let (host, port) = match request.method {
+            CONNECT => match parse_authority(request.path.as_slice()) {
+                Some(target) => target,
+                None => return Ok(false),
+            },
+            _ => return Ok(false),
+        };
+
+        let mut target = match TcpStream::connect(host.as_slice(), port) {
+            Ok(target) => target,
+            Err(e) => {
+                info!("CONNECT to {}:{} failed: {}", host, port, e);
+                let mut headers = HashMap::new();
+                headers.insert(b"Content-Length".to_vec(), b"0".to_vec());
+                headers.insert(b"Connection".to_vec(), b"close".to_vec());
+                try!(start_http_response(writer, request.version, HTTP502, None, &headers, true));
+                return Ok(true)
+            },
+        };
+
+        try!(writer.write_str("HTTP/1.1 200 Connection Established\r\n\r\n"));
+        try!(writer.flush());
+
+        let mut from_target = target.clone();
+        let mut to_client = writer.get_ref().clone();
+        spawn(proc() {
+            let _ = copy_stream(&mut from_target, &mut to_client);
+            let _ = to_client.close_write();
+        });
+
+        // reading through `reader` keeps anything the client sent early
+        let result = copy_stream(reader, &mut target);
+        let _ = target.close_write();
+        result.map(|()| true)
+    }
+}
+
+
+// Splits a CONNECT target like "example.com:443" or "[::1]:8080".
+fn parse_authority(authority: &[u8]) -> Option<(String, u16)> {
+    let colon = match authority.iter().rposition(|b| *b == b':') {
+        Some(i) => i,
+        None => return None,
+    };
+    let host = match authority.slice_to(colon) {
+        b"" => return None,
+        h if h.starts_with(b"[") && h.ends_with(b"]") => h.slice(1, h.len() - 1),
+        h => h,
+    };
+    if host.is_empty() || host.iter().any(|b| *b <= b' ' || *b == b'/' || *b == b'@') {
+        return None
+    }
+    let port = match from_utf8(authority.slice_from(colon + 1)).and_then(from_str::<u16>) {
+        Some(0) | None => return None,
+        Some(port) => port,
+    };
+    from_utf8(host).map(|h| (h.to_string(), port))
+}
+
+
+fn copy_stream<R: Reader, W: Writer>(from: &mut R, to: &mut W) -> IoResult<()> {
+    let mut buf = [0u8, ..8192];
+    loop {
+        match from.read(buf) {
+            Ok(count) => {
+                try!(to.write(buf.slice_to(count)));
+                try!(to.flush());
+            },
+            Err(ref e) if e.kind == EndOfFile => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+
+// Counts open connections per client address for
+// `ServerConfig::max_connections_per_ip`.
+struct ConnectionLimiter {
+    max: Option<uint>,
+    counts: Mutex<HashMap<IpAddr, uint>>,
+}
+
+
+impl ConnectionLimiter {
+    fn new(config: &ServerConfig) -> ConnectionLimiter {
+        ConnectionLimiter{max: config.max_connections_per_ip, counts: Mutex::new(HashMap::new())}
+    }
+
+    // Connections whose address is unknown are never refused.
+    fn acquire(&self, ip: Option<IpAddr>) -> bool {
+        match (self.max, ip) {
+            (Some(max), Some(ip)) => {
+                let mut counts = self.counts.lock();
+                let count = counts.find_or_insert(ip, 0);
+                if *count >= max {
+                    return false
+                }
+                *count += 1;
+                true
+            },
+            _ => true,
+        }
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        match (self.max, ip) {
+            (Some(_), Some(ip)) => {
+                let mut counts = self.counts.lock();
+                let remove = match counts.find_mut(&ip) {
+                    Some(count) => {
+                        *count -= 1;
+                        *count == 0
+                    },
+                    None => false,
+                };
+                if remove {
+                    counts.remove(&ip);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+
+// Serves `stream` in a task of its own unless its client already holds too
+// many connections.
+fn spawn_tcp_connection
+    <'req, T: HTTPRequestHandler<'req, TcpStream, TcpStream> + Send + Sync + Sized, S: Spawner>
+    (mut stream: TcpStream,
+     handler: &Arc<T>,
+     config: &Arc<ServerConfig>,
+     limiter: &Arc<ConnectionLimiter>,
+     builder: TaskBuilder<S>)
+{
+    let peer_ip = stream.peer_name().ok().map(|addr| addr.ip);
+    if !limiter.acquire(peer_ip) {
+        debug!("Refused a connection from {}: too many connections", peer_ip);
+        return
+    }
+    set_tcp_options(&mut stream, &**config);
+    let new_handler = handler.clone();
+    let new_config = config.clone();
+    let new_limiter = limiter.clone();
+
+    builder.spawn(proc() {
+        // serve_connection catches panics, so the slot is always given back
+        serve_connection(new_handler, new_config, stream);
+        new_limiter.release(peer_ip);
+    });
+}
+
+
+fn set_tcp_options(stream: &mut TcpStream, config: &ServerConfig) {
+    match stream.set_nodelay(config.tcp_nodelay) {
+        Ok(()) => {},
+        Err(e) => debug!("Could not set TCP_NODELAY: {}", e),
+    }
+}
+
+
+pub fn multi_thread_http_serve
+    <'req, T: HTTPRequestHandler<'req, TcpStream, TcpStream> + Send + Sync + Sized>
+    (host: &str, port: u16, handler: Arc<T>, config: ServerConfig)
+     -> IoResult<()>
+{
+    multi_thread_http_serve_listener(try!(TcpListener::bind(host, port)), handler, config)
+}
+
+
+// Like `multi_thread_http_serve`, but on a listener bound by the caller, e.g.
+// to port 0 with the port actually chosen read from its `socket_name`.
+pub fn multi_thread_http_serve_listener
+    <'req, T: HTTPRequestHandler<'req, TcpStream, TcpStream> + Send + Sync + Sized>
+    (listener: TcpListener, handler: Arc<T>, config: ServerConfig)
+     -> IoResult<()>
+{
+    let acceptor = try!(listener.listen());
+    let limiter = Arc::new(ConnectionLimiter::new(&config));
+    multi_thread_accept(acceptor, handler, Arc::new(config), limiter)
+}
+
+
+// Serves one handler on several addresses at once, every address getting
+// its own accept loop. Returns once all of the loops have stopped, either
+// shut down through `ServerConfig::shutdown` or because one of them failed.
+// A failing loop triggers the shutdown of the others, and its error is
+// returned.
+pub fn multi_address_http_serve
+    <'req, T: HTTPRequestHandler<'req, TcpStream, TcpStream> + Send + Sync + Sized>
+    (addresses: Vec<(String, u16)>, handler: Arc<T>, config: ServerConfig)
+     -> IoResult<()>
+{
+    let limiter = Arc::new(ConnectionLimiter::new(&config));
+    let config = Arc::new(config);
+
+    let mut acceptors = Vec::with_capacity(addresses.len());
+    for &(ref host, port) in addresses.iter() {
+        acceptors.push(try!(try!(TcpListener::bind(host.as_slice(), port)).listen()));
+    }
+
+    let (tx, rx) = channel();
+    let count = acceptors.len();
+    for acceptor in acceptors.into_iter() {
+        let (tx, new_handler, new_config, new_limiter) =
+            (tx.clone(), handler.clone(), config.clone(), limiter.clone());
+        spawn(proc() {
+            tx.send(multi_thread_accept(acceptor, new_handler, new_config, new_limiter));
+        });
+    }
+
+    let mut result = Ok(());
+    for _ in range(0, count) {
+        match rx.recv() {
+            Ok(()) => {},
+            Err(e) => {
+                error!("Accept loop failed: {}", e);
+                if result.is_ok() {
+                    config.shutdown.shutdown();
+                    result = Err(e);
+                }
+            },
+        }
+    }
+    result
+}
+
+
+fn multi_thread_accept
+    <'req, T: HTTPRequestHandler<'req, TcpStream, TcpStream> + Send + Sync + Sized>
+    (mut acceptor: TcpAcceptor,
+     handler: Arc<T>,
+     config: Arc<ServerConfig>,
+     limiter: Arc<ConnectionLimiter>)
+     -> IoResult<()>
+{
+    config.shutdown.watch(WatchedTcp(acceptor.clone()));
+    let mut backoff = AcceptBackoff::new(&*config);
+    for stream in acceptor.incoming() {
+        match stream {
+            Ok(stream) => {
+                backoff.reset();
+                spawn_tcp_connection(stream, &handler, &config, &limiter, TaskBuilder::new());
+            },
+            Err(_) if config.shutdown.is_requested() => break,
+            Err(e) => try!(backoff.wait(e)),
+        }
+    }
+    Ok(())
+}
+
+
+// Doubles the pause after every failed accept in a row, so that a
+// persistent error does not turn the accept loop into a busy loop.
+struct AcceptBackoff {
+    delay_ms: u64,
+    max_delay_ms: u64,
+}
+
+
+impl AcceptBackoff {
+    fn new(config: &ServerConfig) -> AcceptBackoff {
+        AcceptBackoff{delay_ms: 0, max_delay_ms: config.max_accept_backoff_ms}
+    }
+
+    fn reset(&mut self) {
+        self.delay_ms = 0;
+    }
+
+    // Errors meaning that the acceptor is gone for good are handed back.
+    fn wait(&mut self, error: IoError) -> IoResult<()> {
+        if error.kind == EndOfFile {
+            return Err(error)
+        }
+        self.delay_ms = min(max(self.delay_ms * 2, 10), self.max_delay_ms);
+        error!("Could not accept a connection, retrying in {}ms: {}", self.delay_ms, error);
+        sleep(Duration::milliseconds(self.delay_ms as i64));
+        Ok(())
+    }
+}
+
+
+// Stops the accept loops of the servers whose config holds it or a clone
+// of it, e.g. from a task waiting for a signal, making the serve functions
+// return. Connections already accepted are still served to their end.
+#[deriving(Clone)]
+pub struct Shutdown {
+    state: Arc<Mutex<ShutdownState>>,
+}
+
+
+struct ShutdownState {
+    requested: bool,
+    acceptors: Vec<WatchedAcceptor>,
+}
+
+
+enum WatchedAcceptor {
+    WatchedTcp(TcpAcceptor),
+    WatchedUnix(UnixAcceptor),
+}
+
+
+impl WatchedAcceptor {
+    fn close(&mut self) {
+        let result = match *self {
+            WatchedTcp(ref mut acceptor) => acceptor.close_accept(),
+            WatchedUnix(ref mut acceptor) => acceptor.close_accept(),
+        };
+        match result {
+            Ok(()) => {},
+            Err(e) => error!("Could not stop accepting connections: {}", e),
+        }
+    }
+}
+
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown{state: Arc::new(Mutex::new(ShutdownState{requested: false, acceptors: vec![]}))}
+    }
+
+    pub fn shutdown(&self) {
+        let mut state = self.state.lock();
+        state.requested = true;
+        for acceptor in state.acceptors.iter_mut() {
+            acceptor.close();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.state.lock().requested
+    }
+
+    // An acceptor watched once the shutdown was requested is closed at once.
+    fn watch(&self, mut acceptor: WatchedAcceptor) {
+        let mut state = self.state.lock();
+        if state.requested {
+            acceptor.close();
+        } else {
+            state.acceptors.push(acceptor);
+        }
+    }
+}
+
+
+pub fn green_http_serve
+    <'req, T: HTTPRequestHandler<'req, TcpStream, TcpStream> + Send + Sync + Sized>
+    (host: &str, port: u16, handler: Arc<T>, config: ServerConfig)
+     -> IoResult<()>
+{
+    green_http_serve_listener(try!(TcpListener::bind(host, port)), handler, config)
+}
+
+
+// Like `green_http_serve`, but on a listener bound by the caller.
+pub fn green_http_serve_listener
+    <'req, T: HTTPRequestHandler<'req, TcpStream, TcpStream> + Send + Sync + Sized>
+    (listener: TcpListener, handler: Arc<T>, config: ServerConfig)
+     -> IoResult<()>
+{
+    let limiter = Arc::new(ConnectionLimiter::new(&config));
+    let config = Arc::new(config);
+    let mut pool = SchedPool::new(PoolConfig::new());
+
+    let mut acceptor = try!(listener.listen());
+    config.shutdown.watch(WatchedTcp(acceptor.clone()));
+
+    let mut backoff = AcceptBackoff::new(&*config);
+    for stream in acceptor.incoming() {
+        match stream {
+            Ok(stream) => {
+                backoff.reset();
+                spawn_tcp_connection(stream, &handler, &config, &limiter,
+                                     TaskBuilder::new().green(&mut pool));
+            },
+            Err(_) if config.shutdown.is_requested() => break,
+            Err(e) => try!(backoff.wait(e)),
+        }
+    }
+    Ok(())
+}
+
+
+// Serves requests arriving on a Unix domain socket at `path`. A stale
+// socket file left behind by a previous run is replaced, and the socket
+// file is removed again once the server stops accepting. Anything else
+// already at `path`, including a socket some server still listens on, is
+// left alone and an error returned.
+pub fn unix_http_serve
+    <'req, T: HTTPRequestHandler<'req, UnixStream, UnixStream> + Send + Sync + Sized>
+    (path: &str, handler: Arc<T>, config: ServerConfig)
+     -> IoResult<()>
+{
+    let config = Arc::new(config);
+    let socket_path = Path::new(path);
+    match lstat(&socket_path) {
+        // sockets are of no type the file system API knows
+        Ok(ref stat) if stat.kind == TypeUnknown => {
+            if UnixStream::connect(&socket_path).is_ok() {
+                return Err(IoError{
+                    kind: PathAlreadyExists,
+                    desc: "socket is in use by another server",
+                    detail: Some(path.to_string()),
+                })
+            }
+            try!(unlink(&socket_path));
+        },
+        Ok(_) => return Err(IoError{
+            kind: PathAlreadyExists,
+            desc: "not a socket",
+            detail: Some(path.to_string()),
+        }),
+        Err(ref e) if e.kind == FileNotFound => {},
+        Err(e) => return Err(e),
+    }
+
+    let mut acceptor = try!(try!(UnixListener::bind(&socket_path)).listen());
+    config.shutdown.watch(WatchedUnix(acceptor.clone()));
+
+    let mut result = Ok(());
+    let mut backoff = AcceptBackoff::new(&*config);
+    for stream in acceptor.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) if config.shutdown.is_requested() => break,
+            Err(e) => match backoff.wait(e) {
+                Ok(()) => continue,
+                Err(e) => {
+                    result = Err(e);
+                    break
+                },
+            },
+        };
+        backoff.reset();
+        let new_handler = handler.clone();
+        let new_config = config.clone();
+
+        spawn(proc() {
+            serve_connection(new_handler, new_config, stream)
+        });
+    }
+
+    drop(acceptor);
+    match unlink(&socket_path) {
+        Ok(()) => {},
+        Err(e) => error!("Could not remove {}: {}", path, e),
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::cmp::min;
+    use std::collections::HashMap;
+    use std::io::{BufferedReader, BufferedWriter, MemReader, IoResult, IoError, TimedOut, InvalidInput,
+                  EndOfFile, OtherIoError, TempDir, File, Seek};
+    use std::io::{TcpListener, TcpStream, Listener, Acceptor};
+    use std::io::net::pipe::UnixStream;
+    use std::io::fs::{PathExtensions, readdir};
+    use std::io::timer::sleep;
+    use std::rand::random;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUint, SeqCst};
+    use std::time::duration::Duration;
+    use time::{Timespec, get_time};
+
+    use super::{safe_path_join, parse_content_type, negotiate_language, attachment_disposition,
+                byte_range, WholeBody, Satisfiable, Unsatisfiable, parse_request_head, GET, HTTP11,
+                HTTP400, Strict, Lenient, handle_http, ServerConfig, Clock, ConnectionTimeouts,
+                HTTPRequestHandler, HTTPRequest, HTTPHeaders, HTTPHeadersExt, HTTPResponseCode,
+                HTTPResponseWriter, BodyReader, BytesResponseWriter, HTTP200, should_keep_alive,
+                HTTP10, UploadHandler, HTTP401, download_response,
+                parse_http_request, HTTPMethod, POST, HEAD, PUT, DELETE, PATCH, CONNECT, OPTIONS,
+                TRACE, NoMethod, Router, multi_address_http_serve, serve_connection,
+                accepted_response, no_content_response, HTTP204, Mount, read_until_limited,
+                unix_http_serve, ConnectHandler, parse_authority, ConcatResponseWriter, StripSlash,
+                AppendSlash, bytes_etag, green_http_serve_listener, live_connections,
+                created_response, multi_thread_http_serve_listener, FaviconHandler, AcceptBackoff,
+                canonical_header_name, TextResponseWriter, upgrade_required_response,
+                ChannelResponseWriter};
+
+    // A connection whose client sent all of `input` up front, handed to the
+    // server at most `read_size` bytes per read.
+    #[deriving(Clone)]
+    struct MockStream {
+        input: Arc<Mutex<MemReader>>,
+        output: Arc<Mutex<Vec<u8>>>,
+        read_size: uint,
+        // every idle timeout the server set, in order
+        idle_timeouts: Arc<Mutex<Vec<Option<u64>>>>,
+        // how many writes the output came in
+        writes: Arc<AtomicUint>,
+    }
+
+    impl MockStream {
+        fn new(input: &[u8]) -> MockStream {
+            MockStream{
+                input: Arc::new(Mutex::new(MemReader::new(input.to_vec()))),
+                output: Arc::new(Mutex::new(vec![])),
+                read_size: 65536,
+                idle_timeouts: Arc::new(Mutex::new(vec![])),
+                writes: Arc::new(AtomicUint::new(0)),
+            }
+        }
+
+        fn trickling(input: &[u8]) -> MockStream {
+            let mut stream = MockStream::new(input);
+            stream.read_size = 1;
+            stream
+        }
+
+        fn output(&self) -> String {
+            String::from_utf8_lossy(self.output.lock().as_slice()).into_string()
+        }
+    }
+
+    impl Reader for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            let wanted = min(buf.len(), self.read_size);
+            self.input.lock().read(buf.slice_to_mut(wanted))
+        }
+    }
+
+    impl Writer for MockStream {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            self.writes.fetch_add(1, SeqCst);
+            self.output.lock().push_all(buf);
+            Ok(())
+        }
+    }
+
+    impl ConnectionTimeouts for MockStream {
+        fn set_idle_timeout(&mut self, timeout_ms: Option<u64>) {
+            self.idle_timeouts.lock().push(timeout_ms);
+        }
+
+        fn set_write_timeout(&mut self, _: Option<u64>) {}
+    }
+
+    // Moves on by `step_ms` every time it is read.
+    struct StepClock {
+        ms: AtomicUint,
+        step_ms: uint,
+    }
+
+    impl StepClock {
+        fn new(step_ms: uint) -> StepClock {
+            StepClock{ms: AtomicUint::new(0), step_ms: step_ms}
+        }
+    }
+
+    impl Clock for StepClock {
+        fn now(&self) -> Timespec {
+            let ms = self.ms.fetch_add(self.step_ms, SeqCst);
+            Timespec::new((ms / 1000) as i64, (ms % 1000 * 1000000) as i32)
+        }
+    }
+
+    type Response = IoResult<Option<(HTTPResponseCode,
+                                     Box<HTTPHeaders>,
+                                     Box<HTTPResponseWriter<MockStream> + 'static>)>>;
+
+    struct FnHandler {
+        handle: fn(&HTTPRequest, &mut BodyReader<MockStream>) -> Response,
+    }
+
+    impl HTTPRequestHandler<'static, MockStream, MockStream> for FnHandler {
+        fn handle(&self, request: &HTTPRequest, body: &mut BodyReader<MockStream>) -> Response {
+            (self.handle)(request, body)
+        }
+    }
+
+    fn hello(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let writer: Box<HTTPResponseWriter<MockStream>> =
+            BytesResponseWriter::<MockStream>::new(b"hello".to_vec());
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    // Serves `stream` until the server closes it.
+    fn serve(handler: &HTTPRequestHandler<'static, MockStream, MockStream>,
+             config: &ServerConfig,
+             stream: &MockStream)
+             -> IoResult<()>
+    {
+        handle_http(handler, config, &AtomicBool::new(false),
+                    BufferedReader::new(stream.clone()), BufferedWriter::new(stream.clone()))
+    }
+
+    fn responses(output: &str) -> uint {
+        output.match_indices("HTTP/1.1 ").count()
+    }
+
+    // Everything the server sends back on a connection carrying `input`.
+    fn respond(handler: &HTTPRequestHandler<'static, MockStream, MockStream>,
+               config: &ServerConfig,
+               input: &[u8])
+               -> String
+    {
+        let stream = MockStream::new(input);
+        assert!(serve(handler, config, &stream).is_ok());
+        stream.output()
+    }
+
+    #[test]
+    fn test_safe_path_join() {
+        let root = Path::new("/srv/www");
+        assert!(safe_path_join(&root, b"/docs/a%20b.txt") == Some(Path::new("/srv/www/docs/a b.txt")));
+        assert!(safe_path_join(&root, b"/docs/../index.html") == Some(Path::new("/srv/www/index.html")));
+        assert!(safe_path_join(&root, b"//etc/passwd") == Some(Path::new("/srv/www/etc/passwd")));
+        assert!(safe_path_join(&root, b"/") == Some(Path::new("/srv/www")));
+    }
+
+    #[test]
+    fn test_safe_path_join_escapes() {
+        let root = Path::new("/srv/www");
+        for path in [b"/../etc/passwd", b"/docs/../../etc/passwd", b"/%2e%2e/etc/passwd",
+                     b"/%2E%2E%2fetc/passwd", b"/a%00.txt", b"/..\\etc", b"/C:/Windows",
+                     b"/%zz", b"/%2"].iter() {
+            assert!(safe_path_join(&root, *path).is_none());
+        }
+    }
+
+    #[test]
+    fn test_parse_content_type() {
+        let (media_type, params) = parse_content_type(b"Text/HTML; Charset=\"utf-8\"; boundary=x");
+        assert_eq!(media_type, b"text/html".to_vec());
+        assert_eq!(params, vec![(b"charset".to_vec(), b"utf-8".to_vec()),
+                                (b"boundary".to_vec(), b"x".to_vec())]);
+
+        let (media_type, params) = parse_content_type(b"multipart/form-data; boundary=\"a\\\"b;c\"");
+        assert_eq!(media_type, b"multipart/form-data".to_vec());
+        assert_eq!(params, vec![(b"boundary".to_vec(), b"a\"b;c".to_vec())]);
+    }
+
+    #[test]
+    fn test_negotiate_language() {
+        assert_eq!(negotiate_language(b"fr-CH, fr;q=0.9, en;q=0.8", &["en", "fr"]), Some("fr"));
+        assert_eq!(negotiate_language(b"da, en-gb;q=0.8", &["en-US", "en-GB"]), Some("en-GB"));
+        assert_eq!(negotiate_language(b"en", &["de", "en-US"]), Some("en-US"));
+        assert_eq!(negotiate_language(b"de;q=0.5, en", &["de", "en"]), Some("en"));
+        assert_eq!(negotiate_language(b"en;q=0", &["en"]), None);
+        assert_eq!(negotiate_language(b"*", &["de", "en"]), Some("de"));
+    }
+
+    #[test]
+    fn test_attachment_disposition() {
+        assert_eq!(attachment_disposition("report.pdf"),
+                   b"attachment; filename=\"report.pdf\"".to_vec());
+        assert_eq!(attachment_disposition("a \"b\".txt"),
+                   b"attachment; filename=\"a \\\"b\\\".txt\"".to_vec());
+        assert_eq!(attachment_disposition("résumé 1.txt"),
+                   b"attachment; filename=\"r_sum_ 1.txt\"; filename*=UTF-8''r%C3%A9sum%C3%A9%201.txt".to_vec());
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range(Some(b"bytes=0-4"), 10), Satisfiable(0, 4));
+        assert_eq!(byte_range(Some(b"bytes=5-"), 10), Satisfiable(5, 9));
+        assert_eq!(byte_range(Some(b"bytes=-3"), 10), Satisfiable(7, 9));
+        assert_eq!(byte_range(Some(b"bytes=-30"), 10), Satisfiable(0, 9));
+        assert_eq!(byte_range(Some(b"bytes=8-100"), 10), Satisfiable(8, 9));
+        assert_eq!(byte_range(Some(b"bytes=10-"), 10), Unsatisfiable);
+        assert_eq!(byte_range(Some(b"bytes=-0"), 10), Unsatisfiable);
+        assert_eq!(byte_range(Some(b"bytes=0-1,3-4"), 10), WholeBody);
+        assert_eq!(byte_range(Some(b"bytes=4-2"), 10), WholeBody);
+        assert_eq!(byte_range(Some(b"items=0-1"), 10), WholeBody);
+        assert_eq!(byte_range(None, 10), WholeBody);
+    }
+
+    #[test]
+    fn test_parse_request_head() {
+        let head = b"GET /a?b HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\nbody";
+        match parse_request_head(head, Strict) {
+            Ok(Some((request, length))) => {
+                assert_eq!(request.method, GET);
+                assert_eq!(request.path, b"/a?b");
+                assert_eq!(request.version, HTTP11);
+                assert_eq!(request.headers.len(), 2);
+                assert_eq!(length, head.len() - 4);
+            },
+            _ => assert!(false),
+        }
+        match parse_request_head(b"GET /a HTTP/1.1\r\nHost: x\r\n", Strict) {
+            Ok(None) => {},
+            _ => assert!(false),
+        }
+        assert!(parse_request_head(b"GET /a HTTP/1.1\r\n\r\n", Strict).is_err());
+        assert!(parse_request_head(b"GET /a HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n", Strict)
+                .err() == Some(HTTP400));
+    }
+
+    // whatever arrives, parsing must fail cleanly rather than panic
+    #[test]
+    fn test_parse_request_head_random_bytes() {
+        let valid = b"POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\n\r\n";
+        for _ in range(0u, 20000) {
+            let mut buf: Vec<u8> = if random::<bool>() {
+                valid.to_vec()
+            } else {
+                range(0, random::<uint>() % 128).map(|_| random::<u8>()).collect()
+            };
+            for _ in range(0, random::<uint>() % 4 + 1) {
+                if !buf.is_empty() {
+                    let i = random::<uint>() % buf.len();
+                    buf.as_mut_slice()[i] = random::<u8>();
+                }
+            }
+            let truncated = random::<uint>() % (buf.len() + 1);
+            buf.truncate(truncated);
+            let _ = parse_request_head(buf.as_slice(), Strict);
+            let _ = parse_request_head(buf.as_slice(), Lenient);
+        }
+    }
+
+    // Whatever comes in on the connection, parsing ends with a request, a
+    // status to answer with or an error, never a panic.
+    #[test]
+    fn test_parse_http_request_random_bytes() {
+        let valid = "POST /upload?a=b HTTP/1.1\r\nHost: example.com\r\n\
+                     Transfer-Encoding: chunked\r\nX-Empty:\r\n\r\n".as_bytes();
+        for _ in range(0u, 5000) {
+            let mut buf: Vec<u8> = if random::<bool>() {
+                valid.to_vec()
+            } else {
+                range(0, random::<uint>() % 128).map(|_| random::<u8>()).collect()
+            };
+            for _ in range(0, random::<uint>() % 4 + 1) {
+                if !buf.is_empty() {
+                    // bytes meaningful to the parser make the likeliest trouble
+                    let noise = [b'\r', b'\n', b' ', b':', 0, random::<u8>()];
+                    let i = random::<uint>() % buf.len();
+                    buf.as_mut_slice()[i] = noise[random::<uint>() % noise.len()];
+                }
+            }
+            let truncated = random::<uint>() % (buf.len() + 1);
+            buf.truncate(truncated);
+            for strictness in [Strict, Lenient].iter() {
+                let mut config = ServerConfig::new();
+                config.strictness = strictness.clone();
+                config.max_line_length = random::<uint>() % 64 + 1;
+                // small buffers split lines at every possible point
+                let mut reader = BufferedReader::with_capacity(
+                    random::<uint>() % 16 + 1, MemReader::new(buf.clone()));
+                match parse_http_request(&mut reader, &config) {
+                    Ok(Ok(_)) | Ok(Err(_)) | Err(_) => {},
+                }
+            }
+        }
+    }
+
+    static SLOW_HEAD: &'static [u8] =
+        b"GET / HTTP/1.1\r\nHost: example.com\r\nUser-Agent: one byte after another\r\nAccept: text/html, text/plain, application/json, */*\r\n\r\n";
+
+    #[test]
+    fn test_trickling_head_is_cut_off() {
+        let mut config = ServerConfig::new();
+        // a byte every 100ms is 10 bytes/s, well below the minimum rate
+        config.clock = box StepClock::new(100);
+        let stream = MockStream::trickling(SLOW_HEAD);
+        match serve(&FnHandler{handle: hello}, &config, &stream) {
+            Err(ref e) if e.kind == TimedOut => {},
+            _ => assert!(false),
+        }
+        assert_eq!(stream.output(), "".to_string());
+        // once the head started, a stalled read gives up at the header deadline
+        assert_eq!(*stream.idle_timeouts.lock(), vec![Some(60000), Some(10000)]);
+    }
+
+    #[test]
+    fn test_slow_start_gets_a_grace_period() {
+        let mut config = ServerConfig::new();
+        config.clock = box StepClock::new(10);
+        let stream = MockStream::trickling(SLOW_HEAD);
+        assert!(serve(&FnHandler{handle: hello}, &config, &stream).is_ok());
+        assert!(stream.output().as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
+
+    #[test]
+    fn test_should_keep_alive() {
+        let config = ServerConfig::new();
+        let mut close = HashMap::new();
+        close.insert(b"Connection".to_vec(), b"close".to_vec());
+        let mut keep_alive = HashMap::new();
+        keep_alive.insert(b"connection".to_vec(), b"Keep-Alive".to_vec());
+
+        assert!(should_keep_alive(HTTP11, &HashMap::new(), &config));
+        assert!(!should_keep_alive(HTTP11, &close, &config));
+        assert!(!should_keep_alive(HTTP10, &HashMap::new(), &config));
+        assert!(should_keep_alive(HTTP10, &keep_alive, &config));
+
+        let mut config = ServerConfig::new();
+        config.keep_alive = false;
+        assert!(!should_keep_alive(HTTP11, &HashMap::new(), &config));
+        assert!(!should_keep_alive(HTTP10, &keep_alive, &config));
+    }
+
+    #[test]
+    fn test_unread_body_is_drained_up_to_a_limit() {
+        let mut config = ServerConfig::new();
+        config.max_drain_bytes = 16;
+        let handler = FnHandler{handle: hello};
+
+        // the handler reads none of it, the next request still gets served
+        let stream = MockStream::new("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 16\r\n\r\n\
+                                       0123456789abcdef\
+                                       GET / HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes());
+        assert!(serve(&handler, &config, &stream).is_ok());
+        assert_eq!(responses(stream.output().as_slice()), 2);
+
+        // too much to throw away, the connection is closed instead
+        let stream = MockStream::new("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 17\r\n\r\n\
+                                       0123456789abcdefg\
+                                       GET / HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes());
+        assert!(serve(&handler, &config, &stream).is_ok());
+        let output = stream.output();
+        assert_eq!(responses(output.as_slice()), 1);
+        assert!(output.as_slice().contains("Connection: close\r\n"));
+    }
+
+    fn upload(file: &[u8]) -> Vec<u8> {
+        let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\n".to_vec();
+        body.push_all(file);
+        body.push_all(b"\r\n--XyZ--\r\n");
+        let mut request = format!("POST /upload HTTP/1.1\r\nHost: x\r\n\
+                                   Content-Type: multipart/form-data; boundary=XyZ\r\n\
+                                   Content-Length: {}\r\n\r\n", body.len()).into_bytes();
+        request.push_all(body.as_slice());
+        request
+    }
+
+    #[test]
+    fn test_upload_lands_on_disk() {
+        let directory = TempDir::new("httpls-upload").unwrap();
+        let handler = UploadHandler::new(directory.path().clone(), 1024);
+        let stream = MockStream::new(upload(b"hello world").as_slice());
+        assert!(serve(&handler, &ServerConfig::new(), &stream).is_ok());
+        let output = stream.output();
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().ends_with(" 11 a.txt\n"));
+
+        let files = readdir(directory.path()).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(File::open(&files.as_slice()[0]).read_to_end().unwrap(), b"hello world".to_vec());
+    }
+
+    #[test]
+    fn test_oversized_upload_is_not_read_through() {
+        let directory = TempDir::new("httpls-upload").unwrap();
+        let handler = UploadHandler::new(directory.path().clone(), 1024);
+        let mut config = ServerConfig::new();
+        config.max_drain_bytes = 1 << 30;
+        let request = upload(Vec::from_elem(1 << 20, b'x').as_slice());
+        let stream = MockStream::new(request.as_slice());
+        assert!(serve(&handler, &config, &stream).is_ok());
+        let output = stream.output();
+        assert!(output.as_slice().starts_with("HTTP/1.1 413 Request Entity Too Large\r\n"));
+        assert!(output.as_slice().contains("Connection: close\r\n"));
+
+        // stopped reading soon after the limit, leaving nothing behind
+        assert!(stream.input.lock().tell().unwrap() < (request.len() / 2) as u64);
+        assert_eq!(readdir(directory.path()).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_line_limits() {
+        let mut config = ServerConfig::new();
+        config.max_line_length = 64;
+        let handler = FnHandler{handle: hello};
+
+        // the request target counts against the line limit as well
+        let long_path = String::from_char(100, '/');
+        let stream = MockStream::new(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", long_path).as_bytes());
+        assert!(serve(&handler, &config, &stream).is_ok());
+        assert!(stream.output().as_slice().starts_with("HTTP/1.1 414 "));
+
+        let long_header = String::from_char(100, 'a');
+        let stream = MockStream::new(format!("GET / HTTP/1.1\r\nHost: x\r\nX-Long: {}\r\n\r\n",
+                                             long_header).as_bytes());
+        assert!(serve(&handler, &config, &stream).is_ok());
+        assert!(stream.output().as_slice().starts_with("HTTP/1.1 400 "));
+
+        let stream = MockStream::new(format!("GET / HTTP/1.1\r\nHost: x\r\nX-Short: {}\r\n\r\n",
+                                             String::from_char(40, 'a')).as_bytes());
+        assert!(serve(&handler, &config, &stream).is_ok());
+        assert!(stream.output().as_slice().starts_with("HTTP/1.1 200 "));
+
+        // a chunk size line over the limit, padded with zeros
+        let mut headers = HashMap::new();
+        headers.insert(b"Transfer-Encoding".to_vec(), b"chunked".to_vec());
+        let chunked = format!("{}5\r\nhello\r\n0\r\n\r\n", String::from_char(100, '0'));
+        let mut reader = BufferedReader::new(MemReader::new(chunked.into_bytes()));
+        let mut body = BodyReader::new(&mut reader, &headers, 64).unwrap();
+        assert_eq!(body.read_to_end().unwrap_err().kind, InvalidInput);
+
+        // a chunk extension within it is fine
+        let chunked = "5;x=y\r\nhello\r\n0\r\n\r\n";
+        let mut reader = BufferedReader::new(MemReader::new(chunked.as_bytes().to_vec()));
+        let mut body = BodyReader::new(&mut reader, &headers, 64).unwrap();
+        assert_eq!(body.read_to_end().unwrap(), b"hello".to_vec());
+    }
+
+    // Answers with the body it read and the Content-Length it was told.
+    fn echo(request: &HTTPRequest, body: &mut BodyReader<MockStream>) -> Response {
+        let mut content = try!(body.read_to_end());
+        content.push(b'|');
+        content.push_all(request.headers.get_header(b"Content-Length").unwrap_or(b""));
+        let writer: Box<HTTPResponseWriter<MockStream>> = BytesResponseWriter::<MockStream>::new(content);
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    fn encoded_post(coding: &str, body: &[u8]) -> Vec<u8> {
+        let mut request = format!("POST / HTTP/1.1\r\nHost: x\r\nContent-Encoding: {}\r\n\
+                                   Content-Length: {}\r\n\r\n", coding, body.len()).into_bytes();
+        request.push_all(body);
+        request
+    }
+
+    // "hello, gzip" gzipped
+    static GZIPPED: [u8, ..31] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48,
+        0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0x48, 0xaf, 0xca, 0x2c, 0x00, 0x00, 0x4a,
+        0x9b, 0xb1, 0x5c, 0x0b, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_encoded_body_is_decoded() {
+        let handler = FnHandler{handle: echo};
+        let stream = MockStream::new(encoded_post("gzip", GZIPPED.as_slice()).as_slice());
+        assert!(serve(&handler, &ServerConfig::new(), &stream).is_ok());
+        assert!(stream.output().as_slice().ends_with("\r\n\r\nhello, gzip|11"));
+
+        // left alone when asked to
+        let mut config = ServerConfig::new();
+        config.accept_encoded_bodies = true;
+        let stream = MockStream::new(encoded_post("gzip", GZIPPED.as_slice()).as_slice());
+        assert!(serve(&handler, &config, &stream).is_ok());
+        let output = stream.output();
+        assert!(output.as_slice().ends_with("|31"));
+        assert!(!output.as_slice().contains("hello"));
+
+        // decoding past the limit
+        let mut config = ServerConfig::new();
+        config.max_decoded_body_bytes = 10;
+        let stream = MockStream::new(encoded_post("gzip", GZIPPED.as_slice()).as_slice());
+        assert!(serve(&handler, &config, &stream).is_ok());
+        assert!(stream.output().as_slice().starts_with("HTTP/1.1 413 "));
+
+        let stream = MockStream::new(encoded_post("deflate", b"not deflate").as_slice());
+        assert!(serve(&handler, &ServerConfig::new(), &stream).is_ok());
+        assert!(stream.output().as_slice().starts_with("HTTP/1.1 400 "));
+    }
+
+    #[test]
+    fn test_unknown_coding_is_unsupported() {
+        let handler = FnHandler{handle: echo};
+        let stream = MockStream::new(encoded_post("br", b"whatever").as_slice());
+        assert!(serve(&handler, &ServerConfig::new(), &stream).is_ok());
+        assert!(stream.output().as_slice().starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+
+        // nothing to decode without a body
+        let stream = MockStream::new(encoded_post("br", b"").as_slice());
+        assert!(serve(&handler, &ServerConfig::new(), &stream).is_ok());
+        assert!(stream.output().as_slice().starts_with("HTTP/1.1 200 "));
+    }
+
+    struct Download {
+        path: Path,
+    }
+
+    impl HTTPRequestHandler<'static, MockStream, MockStream> for Download {
+        fn handle(&self, request: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+            download_response(request, &self.path, "report.txt").map(Some)
+        }
+    }
+
+    #[test]
+    fn test_download() {
+        let directory = TempDir::new("httpls-download").unwrap();
+        let path = directory.path().join("file");
+        File::create(&path).write(b"0123456789").unwrap();
+        let handler = Download{path: path};
+
+        let stream = MockStream::new(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(serve(&handler, &ServerConfig::new(), &stream).is_ok());
+        let output = stream.output();
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().contains("Content-Disposition: attachment; filename=\"report.txt\"\r\n"));
+        assert!(output.as_slice().contains("Content-Length: 10\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\n0123456789"));
+
+        let stream = MockStream::new(b"GET / HTTP/1.1\r\nHost: x\r\nRange: bytes=3-5\r\n\r\n");
+        assert!(serve(&handler, &ServerConfig::new(), &stream).is_ok());
+        let output = stream.output();
+        assert!(output.as_slice().starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(output.as_slice().contains("Content-Range: bytes 3-5/10\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\n345"));
+
+        let stream = MockStream::new(b"GET / HTTP/1.1\r\nHost: x\r\nRange: bytes=10-\r\n\r\n");
+        assert!(serve(&handler, &ServerConfig::new(), &stream).is_ok());
+        let output = stream.output();
+        assert!(output.as_slice().starts_with("HTTP/1.1 416 Requested Range Not Satisfiable\r\n"));
+        assert!(output.as_slice().contains("Content-Range: bytes */10\r\n"));
+    }
+
+    // Turns down every request expecting 100 Continue.
+    struct Unauthorized;
+
+    impl HTTPRequestHandler<'static, MockStream, MockStream> for Unauthorized {
+        fn handle(&self, request: &HTTPRequest, body: &mut BodyReader<MockStream>) -> Response {
+            hello(request, body)
+        }
+
+        fn check_continue(&self, _: &HTTPRequest) -> Response {
+            let writer: Box<HTTPResponseWriter<MockStream>> =
+                BytesResponseWriter::<MockStream>::new(vec![]);
+            Ok(Some((HTTP401, box HashMap::new(), writer)))
+        }
+    }
+
+    #[test]
+    fn test_continue_rejected_with_final_status() {
+        let stream = MockStream::new("POST / HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\n\
+                                      Content-Length: 5\r\n\r\n".as_bytes());
+        assert!(serve(&Unauthorized, &ServerConfig::new(), &stream).is_ok());
+        let output = stream.output();
+        assert!(output.as_slice().starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(!output.as_slice().contains("100 Continue"));
+        assert!(output.as_slice().contains("Connection: close\r\n"));
+
+        let stream = MockStream::new("POST / HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\n\
+                                      Content-Length: 5\r\n\r\nhello".as_bytes());
+        assert!(serve(&FnHandler{handle: hello}, &ServerConfig::new(), &stream).is_ok());
+        assert!(stream.output().as_slice().starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 Ok\r\n"));
+    }
+
+    #[test]
+    fn test_method_round_trip() {
+        for method in [GET, POST, HEAD, PUT, DELETE, PATCH, CONNECT, OPTIONS, TRACE].iter() {
+            assert_eq!(HTTPMethod::from_bytes(method.as_bytes()), Some(*method));
+            assert_eq!(format!("{}", method).as_bytes(), method.as_bytes());
+        }
+        assert_eq!(NoMethod.as_bytes(), b"");
+        assert_eq!(HTTPMethod::from_bytes(b""), None);
+        assert_eq!(HTTPMethod::from_bytes(b"get"), None);
+        assert_eq!(HTTPMethod::from_bytes(b"PROPFIND"), None);
+    }
+
+    #[test]
+    fn test_router_methods() {
+        let mut router: Router<'static, MockStream, MockStream> = Router::new();
+        router.add_route(PUT, "/item", box FnHandler{handle: hello});
+        router.add_route(DELETE, "/item", box FnHandler{handle: hello});
+        let config = ServerConfig::new();
+
+        let output = respond(&router, &config, b"DELETE /item HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+
+        let output = respond(&router, &config, b"GET /item HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(output.as_slice().contains("Allow: PUT, DELETE, OPTIONS\r\n"));
+
+        // known to nobody
+        let output = respond(&router, &config, b"PROPFIND /item HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+    }
+
+    #[test]
+    fn test_router_cors_preflight() {
+        let mut router: Router<'static, MockStream, MockStream> = Router::new();
+        router.add_route(GET, "/data", box FnHandler{handle: hello});
+        router.add_route(POST, "/data", box FnHandler{handle: hello});
+        let config = ServerConfig::new();
+        let preflight = "OPTIONS /data HTTP/1.1\r\nHost: x\r\nOrigin: http://app.example\r\n\
+                         Access-Control-Request-Method: POST\r\n\
+                         Access-Control-Request-Headers: content-type\r\n\r\n".as_bytes();
+
+        // no origin allowed: a plain OPTIONS response
+        let output = respond(&router, &config, preflight);
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().contains("Allow: GET, POST, OPTIONS\r\n"));
+        assert!(!output.as_slice().contains("Access-Control-"));
+
+        router.cors_origin = Some("http://app.example".to_string());
+        let output = respond(&router, &config, preflight);
+        assert!(output.as_slice().contains("Allow: GET, POST, OPTIONS\r\n"));
+        assert!(output.as_slice().contains("Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n"));
+        assert!(output.as_slice().contains("Access-Control-Allow-Origin: http://app.example\r\n"));
+        assert!(output.as_slice().contains("Access-Control-Allow-Headers: content-type\r\n"));
+        assert!(output.as_slice().contains("Vary: Origin\r\n"));
+
+        router.cors_origin = Some("*".to_string());
+        let output = respond(&router, &config, preflight);
+        assert!(output.as_slice().contains("Access-Control-Allow-Origin: *\r\n"));
+        assert!(!output.as_slice().contains("Vary: Origin\r\n"));
+
+        router.cors_origin = Some("http://other.example".to_string());
+        let output = respond(&router, &config, preflight);
+        assert!(!output.as_slice().contains("Access-Control-"));
+    }
+
+    // Answers every request with "hello", over any kind of stream.
+    struct Hello;
+
+    impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized>
+        HTTPRequestHandler<'req, R, W>
+        for Hello
+    {
+        fn handle(&self, _: &HTTPRequest, _: &mut BodyReader<R>)
+                  -> IoResult<Option<(HTTPResponseCode,
+                                      Box<HTTPHeaders>,
+                                      Box<HTTPResponseWriter<W> + 'req>)>>
+        {
+            let writer: Box<HTTPResponseWriter<W>> = BytesResponseWriter::<W>::new(b"hello".to_vec());
+            Ok(Some((HTTP200, box HashMap::new(), writer)))
+        }
+    }
+
+    // A local port nothing listens on, as far as can be told.
+    fn free_port() -> u16 {
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        listener.socket_name().unwrap().port
+    }
+
+    // Connects to a local port, waiting for the server to come up.
+    fn connect(port: u16) -> TcpStream {
+        let mut connected = TcpStream::connect("127.0.0.1", port);
+        for _ in range(0u, 200) {
+            if connected.is_ok() { break }
+            sleep(Duration::milliseconds(10));
+            connected = TcpStream::connect("127.0.0.1", port);
+        }
+        connected.unwrap()
+    }
+
+    // Sends `request` to a local port and reads until the server closes the
+    // connection.
+    fn tcp_request(port: u16, request: &[u8]) -> String {
+        let mut stream = connect(port);
+        stream.write(request).unwrap();
+        String::from_utf8_lossy(stream.read_to_end().unwrap().as_slice()).into_string()
+    }
+
+    static CLOSING_GET: &'static [u8] = b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+
+    #[test]
+    fn test_multi_address_serve() {
+        let (first, second) = (free_port(), free_port());
+        let config = ServerConfig::new();
+        let shutdown = config.shutdown.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let addresses = vec![("127.0.0.1".to_string(), first), ("127.0.0.1".to_string(), second)];
+            tx.send(multi_address_http_serve(addresses, Arc::new(Hello), config));
+        });
+        assert!(tcp_request(first, CLOSING_GET).as_slice().ends_with("\r\n\r\nhello"));
+        assert!(tcp_request(second, CLOSING_GET).as_slice().ends_with("\r\n\r\nhello"));
+        // stops both loops
+        shutdown.shutdown();
+        assert!(rx.recv().is_ok());
+
+        // an address which cannot be served is an error, and nothing is
+        // left running
+        let mut taken = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let taken_port = taken.socket_name().unwrap().port;
+        let _acceptor = taken.listen().unwrap();
+        let addresses = vec![("127.0.0.1".to_string(), free_port()), ("127.0.0.1".to_string(), taken_port)];
+        assert!(multi_address_http_serve(addresses, Arc::new(Hello), ServerConfig::new()).is_err());
+    }
+
+    fn panicking(request: &HTTPRequest, body: &mut BodyReader<MockStream>) -> Response {
+        assert!(request.path.as_slice() != b"/panic");
+        hello(request, body)
+    }
+
+    #[test]
+    fn test_panicking_handler_gets_a_500() {
+        let handler = Arc::new(FnHandler{handle: panicking});
+        let config = Arc::new(ServerConfig::new());
+
+        let stream = MockStream::new(b"GET /panic HTTP/1.1\r\nHost: x\r\n\r\n");
+        serve_connection(handler.clone(), config.clone(), stream.clone());
+        let output = stream.output();
+        assert!(output.as_slice().starts_with("HTTP/1.0 500 "));
+        assert!(output.as_slice().contains("Content-Length: 0\r\n"));
+
+        // the same handler goes on serving
+        let stream = MockStream::new(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        serve_connection(handler, config, stream.clone());
+        assert!(stream.output().as_slice().ends_with("\r\n\r\nhello"));
+    }
+
+    // Reads the body a little at a time, answering with how much there was.
+    fn count_body(_: &HTTPRequest, body: &mut BodyReader<MockStream>) -> Response {
+        let mut buf = [0u8, ..100];
+        let mut total = 0u;
+        loop {
+            match body.read(&mut buf) {
+                Ok(n) => total += n,
+                Err(ref e) if e.kind == EndOfFile => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let writer: Box<HTTPResponseWriter<MockStream>> =
+            BytesResponseWriter::<MockStream>::new(total.to_string().into_bytes());
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    #[test]
+    fn test_large_body_is_streamed() {
+        let size = 1u << 20;
+        let mut input = format!("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n", size).into_bytes();
+        input.push_all(Vec::from_elem(size, b'x').as_slice());
+        input.push_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        let mut stream = MockStream::new(input.as_slice());
+        stream.read_size = 1000;
+        assert!(serve(&FnHandler{handle: count_body}, &ServerConfig::new(), &stream).is_ok());
+        let output = stream.output();
+        assert_eq!(responses(output.as_slice()), 2);
+        assert!(output.as_slice().contains("\r\n\r\n1048576HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\n0"));
+    }
+
+    fn resource(request: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        match request.path.as_slice() {
+            b"/queued" => Ok(Some(accepted_response(b"queued".to_vec()))),
+            b"/sloppy" => {
+                let writer: Box<HTTPResponseWriter<MockStream>> =
+                    BytesResponseWriter::<MockStream>::new(b"not sent".to_vec());
+                Ok(Some((HTTP204, box HashMap::new(), writer)))
+            },
+            _ => Ok(Some(no_content_response())),
+        }
+    }
+
+    #[test]
+    fn test_no_content_and_accepted() {
+        let handler = FnHandler{handle: resource};
+        let config = ServerConfig::new();
+
+        let output = respond(&handler, &config, "DELETE /item HTTP/1.1\r\nHost: x\r\n\r\n\
+                                                 GET /sloppy HTTP/1.1\r\nHost: x\r\n\r\n\
+                                                 POST /queued HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n".as_bytes());
+        let parts: Vec<&str> = output.as_slice().split_str("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(parts.len(), 3);
+        // neither a length nor a body, even when the writer has one
+        for part in parts.slice_to(2).iter() {
+            assert!(part.starts_with("204 No Content\r\n"));
+            assert!(part.ends_with("\r\n\r\n"));
+            assert!(!part.contains("Content-Length"));
+            assert!(!part.contains("Content-Type"));
+        }
+        assert!(parts[2].starts_with("202 Accepted\r\n"));
+        assert!(parts[2].contains("Content-Length: 6\r\n"));
+        assert!(parts[2].ends_with("\r\n\r\nqueued"));
+    }
+
+    fn tagged(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let writer: Box<HTTPResponseWriter<MockStream>> =
+            BytesResponseWriter::<MockStream>::with_etag(b"tagged body".to_vec(), b"\"v1\"".to_vec());
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    #[test]
+    fn test_not_modified_has_no_body() {
+        let output = respond(&FnHandler{handle: tagged}, &ServerConfig::new(),
+                             "GET / HTTP/1.1\r\nHost: x\r\nIf-None-Match: \"v1\"\r\n\r\n\
+                              GET / HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes());
+        let parts: Vec<&str> = output.as_slice().split_str("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].starts_with("304 Not Modified\r\n"));
+        assert!(parts[0].contains("ETag: \"v1\"\r\n"));
+        assert!(!parts[0].contains("Content-Length"));
+        assert!(!parts[0].contains("Content-Type"));
+        assert!(parts[0].ends_with("\r\n\r\n"));
+        // the body was not sent ahead of the next response either
+        assert!(parts[1].starts_with("200 Ok\r\n"));
+        assert!(parts[1].ends_with("\r\n\r\ntagged body"));
+    }
+
+    #[test]
+    fn test_host_header_count() {
+        let handler = FnHandler{handle: hello};
+        let config = ServerConfig::new();
+        let bad = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string();
+
+        assert_eq!(respond(&handler, &config, b"GET / HTTP/1.1\r\n\r\n"), bad);
+        assert_eq!(respond(&handler, &config, b"GET / HTTP/1.1\r\nHost: a\r\nhost: b\r\n\r\n"), bad);
+        assert!(respond(&handler, &config, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").as_slice()
+                .starts_with("HTTP/1.1 200 Ok\r\n"));
+        // optional before 1.1
+        assert!(respond(&handler, &config, b"GET / HTTP/1.0\r\n\r\n").as_slice()
+                .starts_with("HTTP/1.0 200 Ok\r\n"));
+        assert_eq!(respond(&handler, &config, b"GET / HTTP/1.0\r\nHost: a\r\nHost: a\r\n\r\n"), bad);
+    }
+
+    // Answers with the path it was given and the prefix mounted above it.
+    fn show_path(request: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let mut content = request.path.clone();
+        content.push(b'|');
+        content.push_all(request.mount_prefix.as_slice());
+        let writer: Box<HTTPResponseWriter<MockStream>> = BytesResponseWriter::<MockStream>::new(content);
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    #[test]
+    fn test_mount() {
+        let mut router: Router<'static, MockStream, MockStream> = Router::new();
+        router.add_route(GET, "/users", box FnHandler{handle: show_path});
+        let mount = Mount::new("/api/", router);
+        let config = ServerConfig::new();
+
+        let output = respond(&mount, &config, b"GET /api/users HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\n/users|/api"));
+        let output = respond(&mount, &config, b"GET /api/users?page=2 HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\n/users?page=2|/api"));
+
+        let output = respond(&mount, &config, b"GET /apidocs HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let output = respond(&mount, &config, b"GET /users HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    // Tells in its body whether anything had reached the client before it
+    // started writing.
+    struct FlushProbe {
+        autoflush: bool,
+    }
+
+    impl HTTPResponseWriter<MockStream> for FlushProbe {
+        fn get_content_length(&self) -> Option<u64> {
+            Some(4)
+        }
+
+        fn get_content_type(&self) -> String {
+            "text/plain".to_string()
+        }
+
+        fn write_data(&self, stream: &mut BufferedWriter<MockStream>) -> IoResult<()> {
+            let sent = !stream.get_ref().output().is_empty();
+            stream.write(if sent { b"sent" } else { b"held" })
+        }
+
+        fn should_autoflush(&self) -> bool {
+            self.autoflush
+        }
+    }
+
+    fn autoflushed(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let writer: Box<HTTPResponseWriter<MockStream>> = box FlushProbe{autoflush: true};
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    fn batched(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let writer: Box<HTTPResponseWriter<MockStream>> = box FlushProbe{autoflush: false};
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    #[test]
+    fn test_autoflush() {
+        let mut config = ServerConfig::new();
+        config.buffer_responses = false;
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+
+        let output = respond(&FnHandler{handle: autoflushed}, &config, request);
+        assert!(output.as_slice().ends_with("\r\n\r\nsent"));
+        // held back until the writer is done, then flushed all the same
+        let output = respond(&FnHandler{handle: batched}, &config, request);
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\nheld"));
+    }
+
+    #[test]
+    fn test_read_until_limited() {
+        let mut reader = BufferedReader::with_capacity(8, MemReader::new(b"GET /a HTTP/1.1".to_vec()));
+        assert_eq!(read_until_limited(&mut reader, b' ', 16, None).unwrap(), Some(b"GET ".to_vec()));
+        assert_eq!(read_until_limited(&mut reader, b' ', 16, None).unwrap(), Some(b"/a ".to_vec()));
+
+        // gives up at the cap, without reading the rest of the token
+        let mut reader = BufferedReader::new(MemReader::new(Vec::from_elem(1 << 20, b'a')));
+        assert_eq!(read_until_limited(&mut reader, b' ', 16, None).unwrap(), None);
+        assert_eq!(reader.read_to_end().unwrap().len(), (1 << 20) - 16);
+    }
+
+    #[test]
+    fn test_head_keeps_content_length() {
+        let config = ServerConfig::new();
+        let output = respond(&FnHandler{handle: hello}, &config, b"HEAD / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().contains("Content-Length: 5\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\n"));
+
+        // nothing of the body gets in the way of the next response
+        let output = respond(&FnHandler{handle: tagged}, &config,
+                             "HEAD / HTTP/1.1\r\nHost: x\r\n\r\n\
+                              GET / HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes());
+        let parts: Vec<&str> = output.as_slice().split_str("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].contains("Content-Length: 11\r\n"));
+        assert!(parts[0].contains("ETag: \"v1\"\r\n"));
+        assert!(parts[0].ends_with("\r\n\r\n"));
+        assert!(parts[1].ends_with("\r\n\r\ntagged body"));
+    }
+
+    #[test]
+    fn test_chunk_extensions() {
+        let output = respond(&FnHandler{handle: echo}, &ServerConfig::new(),
+                             "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                              6;name=value\r\nhello \r\n\
+                              A;quoted=\"a;b\";flag\r\nchunked!!!\r\n\
+                              0;last\r\n\r\n".as_bytes());
+        assert!(output.as_slice().ends_with("\r\n\r\nhello chunked!!!|"));
+    }
+
+    fn failing(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        Err(IoError{kind: OtherIoError, desc: "database unreachable", detail: Some("at <db-1>".to_string())})
+    }
+
+    #[test]
+    fn test_server_error_details() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = respond(&FnHandler{handle: failing}, &ServerConfig::new(), request);
+        assert!(output.as_slice().starts_with("HTTP/1.1 500 Server Error\r\n"));
+        assert!(output.as_slice().contains("Connection: close\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\n<h1>500 Server Error</h1>"));
+
+        let mut config = ServerConfig::new();
+        config.debug = true;
+        let output = respond(&FnHandler{handle: failing}, &config, request);
+        assert!(output.as_slice().contains("database unreachable"));
+        // escaped for the page
+        assert!(output.as_slice().contains("at &lt;db-1&gt;"));
+    }
+
+    #[test]
+    fn test_unix_socket_serve() {
+        let directory = TempDir::new("httpls-unix").unwrap();
+        let socket_path = directory.path().join("http.sock");
+        let path = socket_path.as_str().unwrap().to_string();
+        let config = ServerConfig::new();
+        let shutdown = config.shutdown.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(unix_http_serve(path.as_slice(), Arc::new(Hello), config));
+        });
+
+        let mut connected = UnixStream::connect(&socket_path);
+        for _ in range(0u, 200) {
+            if connected.is_ok() { break }
+            sleep(Duration::milliseconds(10));
+            connected = UnixStream::connect(&socket_path);
+        }
+        let mut stream = connected.unwrap();
+        stream.write(CLOSING_GET).unwrap();
+        let output = String::from_utf8_lossy(stream.read_to_end().unwrap().as_slice()).into_string();
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+
+        shutdown.shutdown();
+        assert!(rx.recv().is_ok());
+        // cleaned up after itself
+        assert!(!socket_path.exists());
+    }
+
+    // Takes over connections to /echo, sending back whatever follows the
+    // request head.
+    struct RawEcho;
+
+    impl HTTPRequestHandler<'static, MockStream, MockStream> for RawEcho {
+        fn handle(&self, request: &HTTPRequest, body: &mut BodyReader<MockStream>) -> Response {
+            hello(request, body)
+        }
+
+        fn take_over(&self,
+                     request: &HTTPRequest,
+                     reader: &mut BufferedReader<MockStream>,
+                     writer: &mut BufferedWriter<MockStream>)
+                     -> IoResult<bool>
+        {
+            if request.path.as_slice() != b"/echo" {
+                return Ok(false)
+            }
+            try!(writer.write(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: echo\r\n\r\n"));
+            let rest = try!(reader.read_to_end());
+            try!(writer.write(rest.as_slice()));
+            try!(writer.flush());
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_take_over() {
+        let output = respond(&RawEcho, &ServerConfig::new(),
+                             "GET /echo HTTP/1.1\r\nHost: x\r\nUpgrade: echo\r\nConnection: upgrade\r\n\r\n\
+                              \x00raw bytes, not HTTP\r\n".as_bytes());
+        assert_eq!(output.as_slice(),
+                   "HTTP/1.1 101 Switching Protocols\r\nUpgrade: echo\r\n\r\n\x00raw bytes, not HTTP\r\n");
+
+        // turned down, the request is handled as usual
+        let output = respond(&RawEcho, &ServerConfig::new(), b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_connect_tunnel() {
+        // sends back everything it gets, once the other side is done
+        let mut target = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let target_port = target.socket_name().unwrap().port;
+        let mut target = target.listen().unwrap();
+        spawn(proc() {
+            let mut stream = target.accept().unwrap();
+            let data = stream.read_to_end().unwrap();
+            stream.write(data.as_slice()).unwrap();
+        });
+
+        let mut proxy = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let proxy_port = proxy.socket_name().unwrap().port;
+        let mut proxy = proxy.listen().unwrap();
+        spawn(proc() {
+            let stream = proxy.accept().unwrap();
+            serve_connection(Arc::new(ConnectHandler), Arc::new(ServerConfig::new()), stream);
+        });
+
+        let mut client = TcpStream::connect("127.0.0.1", proxy_port).unwrap();
+        client.write(format!("CONNECT 127.0.0.1:{0} HTTP/1.1\r\nHost: 127.0.0.1:{0}\r\n\r\nping",
+                             target_port).as_bytes()).unwrap();
+        client.close_write().unwrap();
+        let output = client.read_to_end().unwrap();
+        assert_eq!(String::from_utf8_lossy(output.as_slice()).into_string(),
+                   "HTTP/1.1 200 Connection Established\r\n\r\nping".to_string());
+    }
+
+    #[test]
+    fn test_parse_authority() {
+        assert_eq!(parse_authority(b"example.com:443"), Some(("example.com".to_string(), 443)));
+        assert_eq!(parse_authority(b"[::1]:8080"), Some(("::1".to_string(), 8080)));
+        for target in [b"example.com", b":443", b"example.com:0", b"example.com:http",
+                       b"example.com:65536", b"user@example.com:443", b"/path:80"].iter() {
+            assert_eq!(parse_authority(*target), None);
+        }
+    }
+
+    #[test]
+    fn test_lone_lf_lines() {
+        let request = b"GET / HTTP/1.1\nHost: x\nX-Spaced:no-space  \n\n";
+        let output = respond(&FnHandler{handle: hello}, &ServerConfig::new(), request);
+        assert!(output.as_slice().starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let mut config = ServerConfig::new();
+        config.strictness = Lenient;
+        let output = respond(&FnHandler{handle: hello}, &config, request);
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+    }
+
+    fn three_parts(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let parts: Vec<Box<HTTPResponseWriter<MockStream>>> = vec![
+            BytesResponseWriter::<MockStream>::new(b"one, ".to_vec()),
+            BytesResponseWriter::<MockStream>::new(b"two, ".to_vec()),
+            BytesResponseWriter::<MockStream>::new(b"three".to_vec()),
+        ];
+        Ok(Some((HTTP200, box HashMap::new(), ConcatResponseWriter::new(parts))))
+    }
+
+    #[test]
+    fn test_buffered_response_is_written_at_once() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+        let stream = MockStream::new(request);
+        assert!(serve(&FnHandler{handle: three_parts}, &ServerConfig::new(), &stream).is_ok());
+        assert!(stream.output().as_slice().ends_with("\r\n\r\none, two, three"));
+        assert_eq!(stream.writes.load(SeqCst), 1);
+
+        // the headers go out ahead of the body
+        let mut config = ServerConfig::new();
+        config.buffer_responses = false;
+        let stream = MockStream::new(request);
+        assert!(serve(&FnHandler{handle: three_parts}, &config, &stream).is_ok());
+        assert!(stream.output().as_slice().ends_with("\r\n\r\none, two, three"));
+        assert!(stream.writes.load(SeqCst) > 1);
+    }
+
+    #[test]
+    fn test_content_length() {
+        let mut headers: HTTPHeaders = HashMap::new();
+        assert_eq!(headers.content_length(), None);
+        headers.insert(b"content-length".to_vec(), b"42".to_vec());
+        assert_eq!(headers.content_length(), Some(42));
+        for malformed in [b"", b"-1", b"+1", b"4 2", b"0x10", b"99999999999999999999"].iter() {
+            headers.set_header(b"Content-Length", malformed.to_vec());
+            assert_eq!(headers.content_length(), None);
+        }
+
+        let handler = FnHandler{handle: echo};
+        let config = ServerConfig::new();
+        let output = respond(&handler, &config, b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello");
+        assert!(output.as_slice().ends_with("\r\n\r\nhello|5"));
+        // no body at all
+        let output = respond(&handler, &config, b"POST / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\n|"));
+        let output = respond(&handler, &config, b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: -5\r\n\r\nhello");
+        assert!(output.as_slice().starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let output = respond(&handler, &config, b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: five\r\n\r\nhello");
+        assert!(output.as_slice().starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_trailing_slash_redirects() {
+        let mut router: Router<'static, MockStream, MockStream> = Router::new();
+        router.add_route(GET, "/docs", box FnHandler{handle: hello});
+        router.add_route(GET, "/blog/", box FnHandler{handle: hello});
+        let config = ServerConfig::new();
+
+        let output = respond(&router, &config, b"GET /docs/ HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        router.trailing_slash = StripSlash;
+        let output = respond(&router, &config, b"GET /docs/?page=2 HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(output.as_slice().contains("Location: /docs?page=2\r\n"));
+        // only towards a registered path
+        let output = respond(&router, &config, b"GET /blog HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        router.trailing_slash = AppendSlash;
+        let output = respond(&router, &config, b"GET /blog?tag=rust HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(output.as_slice().contains("Location: /blog/?tag=rust\r\n"));
+        let output = respond(&router, &config, b"GET /docs HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_etag_revalidation() {
+        let etag = bytes_etag(b"tagged body");
+        assert_eq!(etag.len(), 18);
+        assert!(etag == bytes_etag(b"tagged body"));
+        assert!(etag != bytes_etag(b"tagged body!"));
+
+        let handler = FnHandler{handle: tagged};
+        let config = ServerConfig::new();
+        let output = respond(&handler, &config, b"GET / HTTP/1.1\r\nHost: x\r\nIf-None-Match: \"v0\"\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().contains("ETag: \"v1\"\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\ntagged body"));
+
+        for tags in ["\"v0\", \"v1\"", "W/\"v1\"", "*"].iter() {
+            let output = respond(&handler, &config,
+                                 format!("GET / HTTP/1.1\r\nHost: x\r\nIf-None-Match: {}\r\n\r\n", tags).as_bytes());
+            assert!(output.as_slice().starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        }
+    }
+
+    // Decides the connection's fate by the path: /close or /keep-alive.
+    fn connection_choice(request: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let mut headers = box HashMap::new();
+        headers.insert(b"connection".to_vec(), request.path.slice_from(1).to_vec());
+        let writer: Box<HTTPResponseWriter<MockStream>> = BytesResponseWriter::<MockStream>::new(b"ok".to_vec());
+        Ok(Some((HTTP200, headers, writer)))
+    }
+
+    #[test]
+    fn test_handler_chooses_connection() {
+        let handler = FnHandler{handle: connection_choice};
+        let output = respond(&handler, &ServerConfig::new(),
+                             "GET /close HTTP/1.1\r\nHost: x\r\n\r\n\
+                              GET /keep-alive HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes());
+        assert_eq!(responses(output.as_slice()), 1);
+        assert!(output.as_slice().contains("Connection: close\r\n"));
+
+        let mut config = ServerConfig::new();
+        config.keep_alive = false;
+        let output = respond(&handler, &config,
+                             "GET /keep-alive HTTP/1.1\r\nHost: x\r\n\r\n\
+                              GET /close HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes());
+        assert_eq!(responses(output.as_slice()), 2);
+        assert!(output.as_slice().contains("Connection: keep-alive\r\n"));
+    }
+
+    #[test]
+    fn test_idle_connections_are_reaped() {
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut config = ServerConfig::new();
+        config.idle_timeout_ms = 100;
+        let shutdown = config.shutdown.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(green_http_serve_listener(listener, Arc::new(Hello), config));
+        });
+
+        // kept alive after their response, then left alone
+        let mut clients = vec![];
+        for _ in range(0u, 3) {
+            let mut client = connect(port);
+            client.set_read_timeout(Some(5000));
+            client.write(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            clients.push(client);
+        }
+        // the server hangs up once the idle timeout is over
+        for client in clients.iter_mut() {
+            let output = String::from_utf8_lossy(client.read_to_end().unwrap().as_slice()).into_string();
+            assert!(output.as_slice().contains("Connection: keep-alive\r\n"));
+            assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+        }
+        // connections served by other tests come and go meanwhile
+        let mut waited = 0u;
+        while live_connections() > 0 && waited < 5000 {
+            sleep(Duration::milliseconds(10));
+            waited += 10;
+        }
+        assert_eq!(live_connections(), 0);
+
+        shutdown.shutdown();
+        assert!(rx.recv().is_ok());
+    }
+
+    #[test]
+    fn test_path_segment_limit() {
+        let handler = FnHandler{handle: hello};
+        let config = ServerConfig::new();
+        let deep = Vec::from_elem(300, "/a").concat();
+        let output = respond(&handler, &config, format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", deep).as_bytes());
+        assert!(output.as_slice().starts_with("HTTP/1.1 414 "));
+
+        // slashes in the query are not segments
+        let output = respond(&handler, &config,
+                             format!("GET /a?{} HTTP/1.1\r\nHost: x\r\n\r\n", deep).as_bytes());
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+
+        let mut config = ServerConfig::new();
+        config.max_path_segments = 3;
+        let output = respond(&handler, &config, b"GET /a/b/c HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        let output = respond(&handler, &config, b"GET /a/b/c/d HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 414 "));
+    }
+
+    fn create(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        Ok(Some(created_response("/items/7", b"{\"id\": 7}".to_vec())))
+    }
+
+    #[test]
+    fn test_created_response() {
+        let output = respond(&FnHandler{handle: create}, &ServerConfig::new(),
+                             b"POST /items HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(output.as_slice().contains("Location: /items/7\r\n"));
+        assert!(output.as_slice().contains("Content-Length: 9\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\n{\"id\": 7}"));
+    }
+
+    // Reads from a kept-alive connection up to the end of Hello's response.
+    fn read_hello(stream: &mut TcpStream) -> String {
+        let mut output = vec![];
+        let mut buf = [0u8, ..1024];
+        while !output.as_slice().ends_with(b"hello") {
+            let count = stream.read(&mut buf).unwrap();
+            output.push_all(buf.slice_to(count));
+        }
+        String::from_utf8_lossy(output.as_slice()).into_string()
+    }
+
+    #[test]
+    fn test_connections_per_ip() {
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut config = ServerConfig::new();
+        config.max_connections_per_ip = Some(2);
+        let shutdown = config.shutdown.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(multi_thread_http_serve_listener(listener, Arc::new(Hello), config));
+        });
+
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        let mut first = connect(port);
+        first.write(request).unwrap();
+        read_hello(&mut first);
+        let mut second = TcpStream::connect("127.0.0.1", port).unwrap();
+        second.write(request).unwrap();
+        read_hello(&mut second);
+
+        // one too many, closed without a word
+        let mut third = TcpStream::connect("127.0.0.1", port).unwrap();
+        third.set_read_timeout(Some(5000));
+        let _ = third.write(request);
+        match third.read_to_end() {
+            Ok(output) => assert!(output.is_empty()),
+            Err(ref e) => assert!(e.kind != TimedOut),
+        }
+
+        // a slot freed up is taken again
+        drop(first);
+        let mut served = false;
+        for _ in range(0u, 200) {
+            let mut again = TcpStream::connect("127.0.0.1", port).unwrap();
+            again.set_read_timeout(Some(5000));
+            let _ = again.write(CLOSING_GET);
+            match again.read_to_end() {
+                Ok(ref output) if !output.is_empty() => {
+                    served = true;
+                    break
+                },
+                _ => sleep(Duration::milliseconds(10)),
+            }
+        }
+        assert!(served);
+
+        drop(second);
+        shutdown.shutdown();
+        assert!(rx.recv().is_ok());
+    }
+
+    static FLOOD_CHUNKS: uint = 1024;
+
+    // Streams FLOOD_CHUNKS chunks of 64 KB, far more than socket buffers
+    // hold for a client not reading.
+    struct Flood;
+
+    struct FloodWriter;
+
+    impl <W: Writer + Send + Sized> HTTPResponseWriter<W> for FloodWriter {
+        fn get_content_length(&self) -> Option<u64> {
+            None
+        }
+
+        fn get_content_type(&self) -> String {
+            "application/octet-stream".to_string()
+        }
+
+        fn is_streaming(&self) -> bool {
+            true
+        }
+
+        fn write_data(&self, stream: &mut BufferedWriter<W>) -> IoResult<()> {
+            let chunk = Vec::from_elem(65536, b'x');
+            for _ in range(0, FLOOD_CHUNKS) {
+                try!(stream.write(chunk.as_slice()));
+            }
+            stream.flush()
+        }
+    }
+
+    impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized>
+        HTTPRequestHandler<'req, R, W>
+        for Flood
+    {
+        fn handle(&self, _: &HTTPRequest, _: &mut BodyReader<R>)
+                  -> IoResult<Option<(HTTPResponseCode,
+                                      Box<HTTPHeaders>,
+                                      Box<HTTPResponseWriter<W> + 'req>)>>
+        {
+            let writer: Box<HTTPResponseWriter<W>> = box FloodWriter;
+            Ok(Some((HTTP200, box HashMap::new(), writer)))
+        }
+    }
+
+    #[test]
+    fn test_write_timeout() {
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut config = ServerConfig::new();
+        config.write_timeout_ms = Some(200);
+        let shutdown = config.shutdown.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(multi_thread_http_serve_listener(listener, Arc::new(Flood), config));
+        });
+
+        let mut client = connect(port);
+        client.write(CLOSING_GET).unwrap();
+        let mut head = vec![];
+        while !head.as_slice().ends_with(b"\r\n\r\n") {
+            head.push(client.read_byte().unwrap());
+        }
+        // stops reading for a while, and the server gives up on it
+        sleep(Duration::milliseconds(1000));
+        client.set_read_timeout(Some(10000));
+        let body = client.read_to_end().unwrap();
+        assert!(body.len() < FLOOD_CHUNKS * 65536);
+
+        shutdown.shutdown();
+        assert!(rx.recv().is_ok());
+    }
+
+    fn show_upgrade(request: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let upgrade = request.headers.get_header(b"Upgrade").unwrap_or(b"none").to_vec();
+        let writer: Box<HTTPResponseWriter<MockStream>> = BytesResponseWriter::<MockStream>::new(upgrade);
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    #[test]
+    fn test_h2c_upgrade_is_declined() {
+        let output = respond(&FnHandler{handle: show_upgrade}, &ServerConfig::new(),
+                             "GET / HTTP/1.1\r\nHost: x\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                              Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n\
+                              GET / HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\r\n".as_bytes());
+        let parts: Vec<&str> = output.as_slice().split_str("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(parts.len(), 2);
+        assert!(!output.as_slice().contains("101"));
+        assert!(parts[0].starts_with("200 Ok\r\n"));
+        assert!(parts[0].contains("Connection: keep-alive\r\n"));
+        assert!(!parts[0].contains("Upgrade:"));
+        assert!(parts[0].ends_with("\r\n\r\nnone"));
+        // other protocols are the handler's business
+        assert!(parts[1].ends_with("\r\n\r\nwebsocket"));
+    }
+
+    #[test]
+    fn test_favicon() {
+        let config = ServerConfig::new();
+        let handler = FaviconHandler::with_icon(b"\x00\x00\x01\x00icon".to_vec(), FnHandler{handle: hello});
+        let output = respond(&handler, &config, b"GET /favicon.ico HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().contains("Content-Type: image/x-icon\r\n"));
+        assert!(output.as_slice().contains("Cache-Control: public, max-age=86400\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\n\x00\x00\x01\x00icon"));
+
+        // everything else falls through
+        let output = respond(&handler, &config, b"GET /favicon.ico.bak HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+        let output = respond(&handler, &config,
+                             b"POST /favicon.ico HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+
+        let output = respond(&FaviconHandler::new(FnHandler{handle: hello}), &config,
+                             b"GET /favicon.ico?v=2 HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 204 No Content\r\n"));
+    }
+
+    fn many_headers(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let mut headers = box HashMap::new();
+        for i in range(0u, 20) {
+            headers.insert(format!("X-Header-{}", i).into_bytes(), b"value".to_vec());
+        }
+        let writer: Box<HTTPResponseWriter<MockStream>> = BytesResponseWriter::<MockStream>::new(b"ok".to_vec());
+        Ok(Some((HTTP200, headers, writer)))
+    }
+
+    #[test]
+    fn test_response_header_limits() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = respond(&FnHandler{handle: many_headers}, &ServerConfig::new(), request);
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().contains("X-Header-19: value\r\n"));
+
+        let mut config = ServerConfig::new();
+        config.max_response_headers = 10;
+        let output = respond(&FnHandler{handle: many_headers}, &config, request);
+        assert!(output.as_slice().starts_with("HTTP/1.1 500 Server Error\r\n"));
+        assert!(!output.as_slice().contains("X-Header-"));
+
+        let mut config = ServerConfig::new();
+        config.max_response_header_bytes = 100;
+        let output = respond(&FnHandler{handle: many_headers}, &config, request);
+        assert!(output.as_slice().starts_with("HTTP/1.1 500 Server Error\r\n"));
+    }
+
+    #[test]
+    fn test_serve_prebound_listener() {
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        assert!(port != 0);
+        let config = ServerConfig::new();
+        let shutdown = config.shutdown.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(multi_thread_http_serve_listener(listener, Arc::new(Hello), config));
+        });
+        let output = tcp_request(port, CLOSING_GET);
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+
+        shutdown.shutdown();
+        assert!(rx.recv().is_ok());
+    }
+
+    #[test]
+    fn test_accept_backoff() {
+        let mut config = ServerConfig::new();
+        config.max_accept_backoff_ms = 40;
+        let mut backoff = AcceptBackoff::new(&config);
+        let error = || IoError{kind: OtherIoError, desc: "too many open files", detail: None};
+
+        let start = get_time();
+        let mut delays = vec![];
+        for _ in range(0u, 4) {
+            assert!(backoff.wait(error()).is_ok());
+            delays.push(backoff.delay_ms);
+        }
+        // waits out every failure instead of spinning
+        assert!(get_time() - start >= Duration::milliseconds(110));
+        assert_eq!(delays, vec![10, 20, 40, 40]);
+
+        backoff.reset();
+        assert!(backoff.wait(error()).is_ok());
+        assert_eq!(backoff.delay_ms, 10);
+
+        // the acceptor is gone, no point in waiting
+        assert!(backoff.wait(IoError{kind: EndOfFile, desc: "closed", detail: None}).is_err());
+    }
+
+    #[test]
+    fn test_request_target_forms() {
+        let handler = FnHandler{handle: hello};
+        let config = ServerConfig::new();
+        let ok = |input: &[u8], config: &ServerConfig|
+            respond(&handler, config, input).as_slice().starts_with("HTTP/1.1 200 Ok\r\n");
+
+        assert!(ok(b"GET /path HTTP/1.1\r\nHost: x\r\n\r\n", &config));
+        assert!(ok(b"OPTIONS * HTTP/1.1\r\nHost: x\r\n\r\n", &config));
+        assert!(!ok(b"GET foo HTTP/1.1\r\nHost: x\r\n\r\n", &config));
+        assert!(!ok(b"GET * HTTP/1.1\r\nHost: x\r\n\r\n", &config));
+        assert!(!ok(b"GET http://x/path HTTP/1.1\r\nHost: x\r\n\r\n", &config));
+
+        let mut config = ServerConfig::new();
+        config.allow_absolute_form = true;
+        assert!(ok(b"GET http://x/path HTTP/1.1\r\nHost: x\r\n\r\n", &config));
+        assert!(!ok(b"GET foo HTTP/1.1\r\nHost: x\r\n\r\n", &config));
+    }
+
+    #[test]
+    fn test_method_token_whitespace() {
+        let handler = FnHandler{handle: hello};
+        let config = ServerConfig::new();
+        for request in [b"GET\t/ HTTP/1.1\r\nHost: x\r\n\r\n", b" GET / HTTP/1.1\r\nHost: x\r\n\r\n",
+                        b"\tGET / HTTP/1.1\r\nHost: x\r\n\r\n", b"get / HTTP/1.1\r\nHost: x\r\n\r\n"].iter() {
+            let output = respond(&handler, &config, *request);
+            assert!(output.as_slice().starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        }
+        // well formed, but unknown
+        let output = respond(&handler, &config, b"BREW / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+    }
+
+    #[test]
+    fn test_reason_phrase_override() {
+        let mut router: Router<'static, MockStream, MockStream> = Router::new();
+        router.add_route(GET, "/", box FnHandler{handle: hello});
+        let mut config = ServerConfig::new();
+        config.reason_phrases.insert(404, "Nothing Here".to_string());
+
+        let output = respond(&router, &config, b"GET /missing HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 404 Nothing Here\r\n"));
+        // the rest keep theirs
+        let output = respond(&router, &config, b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
+
+    #[test]
+    fn test_head_with_body() {
+        let output = respond(&FnHandler{handle: echo}, &ServerConfig::new(),
+                             "HEAD / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello\
+                              GET / HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes());
+        let parts: Vec<&str> = output.as_slice().split_str("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(parts.len(), 2);
+        // the handler read an empty body, answering just "|5"
+        assert!(parts[0].contains("Content-Length: 2\r\n"));
+        assert!(parts[0].ends_with("\r\n\r\n"));
+        assert!(parts[1].starts_with("200 Ok\r\n"));
+        assert!(parts[1].ends_with("\r\n\r\n|"));
+    }
+
+    #[test]
+    fn test_pipelining_past_the_yield_limit() {
+        let mut input = vec![];
+        for _ in range(0u, 5) {
+            input.push_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        }
+        for yield_after in [0u, 1, 2, 16].iter() {
+            let mut config = ServerConfig::new();
+            config.requests_before_yield = *yield_after;
+            let output = respond(&FnHandler{handle: hello}, &config, input.as_slice());
+            assert_eq!(responses(output.as_slice()), 5);
+            assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+        }
+    }
+
+    fn show_host(request: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let mut content = request.headers.host().unwrap_or(b"").to_vec();
+        content.push(b' ');
+        content.push_all(request.path.as_slice());
+        let writer: Box<HTTPResponseWriter<MockStream>> = BytesResponseWriter::<MockStream>::new(content);
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    #[test]
+    fn test_absolute_form_host_wins() {
+        let mut config = ServerConfig::new();
+        config.allow_absolute_form = true;
+        let handler = FnHandler{handle: show_host};
+
+        let output = respond(&handler, &config,
+                             b"GET http://target.example:8080/a?b HTTP/1.1\r\nHost: other.example\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\ntarget.example:8080 /a?b"));
+        let output = respond(&handler, &config, b"GET http://target.example HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\ntarget.example /"));
+        let output = respond(&handler, &config, b"GET /a HTTP/1.1\r\nHost: other.example\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\nother.example /a"));
+    }
+
+    fn hop_by_hop(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let mut headers = box HashMap::new();
+        headers.insert(b"transfer-encoding".to_vec(), b"chunked".to_vec());
+        headers.insert(b"Connection".to_vec(), b"X-Secret".to_vec());
+        headers.insert(b"X-Secret".to_vec(), b"42".to_vec());
+        headers.insert(b"Proxy-Authenticate".to_vec(), b"Basic".to_vec());
+        headers.insert(b"X-Kept".to_vec(), b"yes".to_vec());
+        let writer: Box<HTTPResponseWriter<MockStream>> = BytesResponseWriter::<MockStream>::new(b"hello".to_vec());
+        Ok(Some((HTTP200, headers, writer)))
+    }
+
+    #[test]
+    fn test_hop_by_hop_headers_are_stripped() {
+        let output = respond(&FnHandler{handle: hop_by_hop}, &ServerConfig::new(),
+                             "GET / HTTP/1.1\r\nHost: x\r\n\r\n\
+                              GET / HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes());
+        let parts: Vec<&str> = output.as_slice().split_str("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(parts.len(), 2);
+        for part in parts.iter() {
+            assert!(!part.contains("Transfer-Encoding"));
+            assert!(!part.contains("X-Secret"));
+            assert!(!part.contains("Proxy-Authenticate"));
+            assert!(part.contains("X-Kept: yes\r\n"));
+            // framed by the server, as it was not chunked after all
+            assert!(part.contains("Content-Length: 5\r\n"));
+            assert!(part.contains("Connection: keep-alive\r\n"));
+            assert!(part.ends_with("\r\n\r\nhello"));
+        }
+    }
+
+    fn never_called(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        assert!(false);
+        Ok(None)
+    }
+
+    #[test]
+    fn test_garbage_gets_the_fixed_400() {
+        let handler = FnHandler{handle: never_called};
+        let config = ServerConfig::new();
+        let bad = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string();
+        // a TLS ClientHello, binary noise, and broken request lines and headers
+        let garbage = [b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03", b"\x00\xff\x00\xff\r\n\r\n",
+                       b"GET / HTTP/1.1 extra\r\n\r\n", b"GET / HTTP/1.1\r\nNo colon here\r\n\r\n",
+                       b"GET / HTTP/1.1\r\nHost : x\r\n\r\n", b"GET\r\n\r\n"];
+        for _ in range(0u, 100) {
+            for input in garbage.iter() {
+                // whatever else follows is not looked at
+                let mut flood = input.to_vec();
+                flood.push_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+                assert_eq!(respond(&handler, &config, flood.as_slice()), bad);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unsupported_versions() {
+        let handler = FnHandler{handle: hello};
+        let config = ServerConfig::new();
+        for version in ["HTTP/2.0", "HTTP/0.9", "HTTP/3.0"].iter() {
+            let output = respond(&handler, &config, format!("GET / {}\r\nHost: x\r\n\r\n", version).as_bytes());
+            assert!(output.as_slice().starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        }
+        for version in ["HTTP/1.x", "HTTP/11", "http/1.1", "HTTP/1.1.1", "HTTP/"].iter() {
+            let output = respond(&handler, &config, format!("GET / {}\r\nHost: x\r\n\r\n", version).as_bytes());
+            assert!(output.as_slice().starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        }
+    }
+
+    #[test]
+    fn test_get_with_body() {
+        let input = "GET / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello\
+                     GET / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n\
+                     GET / HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes();
+
+        // dropped, the handler seeing no body
+        let output = respond(&FnHandler{handle: echo}, &ServerConfig::new(), input);
+        let parts: Vec<&str> = output.as_slice().split_str("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0].ends_with("\r\n\r\n|5"));
+        assert!(parts[1].ends_with("\r\n\r\n|"));
+        assert!(parts[2].ends_with("\r\n\r\n|"));
+
+        let mut config = ServerConfig::new();
+        config.reject_get_bodies = true;
+        let output = respond(&FnHandler{handle: echo}, &config, input);
+        assert_eq!(output, "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string());
+        let output = respond(&FnHandler{handle: echo}, &config,
+                             b"GET / HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
+
+    fn lowercase_headers(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let mut headers = box HashMap::new();
+        headers.insert(b"content-type".to_vec(), b"application/json".to_vec());
+        headers.insert(b"x-request-ID".to_vec(), b"7".to_vec());
+        headers.insert(b"www-authenticate".to_vec(), b"Basic".to_vec());
+        let writer: Box<HTTPResponseWriter<MockStream>> = BytesResponseWriter::<MockStream>::new(b"{}".to_vec());
+        Ok(Some((HTTP200, headers, writer)))
+    }
+
+    #[test]
+    fn test_header_names_are_canonical() {
+        assert_eq!(canonical_header_name(b"content-length"), b"Content-Length".to_vec());
+        assert_eq!(canonical_header_name(b"ETAG"), b"ETag".to_vec());
+        assert_eq!(canonical_header_name(b"x--odd-"), b"X--Odd-".to_vec());
+
+        let output = respond(&FnHandler{handle: lowercase_headers}, &ServerConfig::new(),
+                             b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().contains("\r\nContent-Type: application/json\r\n"));
+        assert!(output.as_slice().contains("\r\nX-Request-Id: 7\r\n"));
+        assert!(output.as_slice().contains("\r\nWWW-Authenticate: Basic\r\n"));
+        assert!(!output.as_slice().contains("content-type"));
+    }
+
+    #[test]
+    fn test_route_listing() {
+        let mut router: Router<'static, MockStream, MockStream> = Router::new();
+        router.add_route(GET, "/users", box FnHandler{handle: hello});
+        router.add_route(POST, "/users", box FnHandler{handle: hello});
+        router.add_host_route(Some("admin.example"), DELETE, "/users", box FnHandler{handle: hello});
+        let config = ServerConfig::new();
+
+        // off unless asked for
+        let output = respond(&router, &config, b"GET /__routes HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        router.list_routes = true;
+        let output = respond(&router, &config, b"GET /__routes HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\nGET /users\nPOST /users\n"));
+        // with the routes of the host asked about
+        let output = respond(&router, &config, b"GET /__routes HTTP/1.1\r\nHost: admin.example\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\nGET /users\nPOST /users\nDELETE admin.example/users\n"));
+    }
+
+    #[test]
+    fn test_keep_alive_hints() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = respond(&FnHandler{handle: hello}, &ServerConfig::new(), request);
+        assert!(output.as_slice().contains("Keep-Alive: timeout=60\r\n"));
+
+        let mut config = ServerConfig::new();
+        config.idle_timeout_ms = 5000;
+        config.max_requests_per_connection = Some(3);
+        let mut input = vec![];
+        for _ in range(0u, 4) {
+            input.push_all(request);
+        }
+        let output = respond(&FnHandler{handle: hello}, &config, input.as_slice());
+        let parts: Vec<&str> = output.as_slice().split_str("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0].contains("Keep-Alive: timeout=5, max=2\r\n"));
+        assert!(parts[1].contains("Keep-Alive: timeout=5, max=1\r\n"));
+        // no hint on a connection about to close
+        assert!(parts[2].contains("Connection: close\r\n"));
+        assert!(!parts[2].contains("Keep-Alive"));
+    }
+
+    fn text(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        let writer: Box<HTTPResponseWriter<MockStream>> = TextResponseWriter::<MockStream>::new("café".to_string());
+        Ok(Some((HTTP200, box HashMap::new(), writer)))
+    }
+
+    #[test]
+    fn test_default_charset() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = respond(&FnHandler{handle: hello}, &ServerConfig::new(), request);
+        assert!(output.as_slice().contains("Content-Type: text/html; charset=utf-8\r\n"));
+
+        let mut config = ServerConfig::new();
+        config.default_charset = "iso-8859-1".to_string();
+        let output = respond(&FnHandler{handle: hello}, &config, request);
+        assert!(output.as_slice().contains("Content-Type: text/html; charset=iso-8859-1\r\n"));
+        // a writer naming its charset keeps it
+        let output = respond(&FnHandler{handle: text}, &config, request);
+        assert!(output.as_slice().contains("Content-Type: text/plain; charset=utf-8\r\n"));
+    }
+
+    fn counted_post(length: uint) -> Vec<u8> {
+        let mut request = format!("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n",
+                                  length).into_bytes();
+        request.push_all(Vec::from_elem(length, b'x').as_slice());
+        request
+    }
+
+    #[test]
+    fn test_slow_body_is_cut_off() {
+        let mut config = ServerConfig::new();
+        // every 100 byte read takes 100ms, so the body comes at 1000 bytes/s
+        config.clock = box StepClock::new(100);
+        config.body_min_bytes_per_sec = Some(2000);
+        config.body_rate_window_ms = 1000;
+        let stream = MockStream::new(counted_post(2000).as_slice());
+        let _ = serve(&FnHandler{handle: count_body}, &config, &stream);
+        let output = stream.output();
+        assert!(output.as_slice().starts_with("HTTP/1.1 500 "));
+        assert!(output.as_slice().contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_body_fast_enough() {
+        let mut config = ServerConfig::new();
+        config.clock = box StepClock::new(100);
+        config.body_min_bytes_per_sec = Some(500);
+        config.body_rate_window_ms = 1000;
+        let stream = MockStream::new(counted_post(2000).as_slice());
+        assert!(serve(&FnHandler{handle: count_body}, &config, &stream).is_ok());
+        let output = stream.output();
+        assert!(output.as_slice().starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\n2000"));
+    }
+
+    fn upgrade_required(_: &HTTPRequest, _: &mut BodyReader<MockStream>) -> Response {
+        Ok(Some(upgrade_required_response("TLS/1.2, HTTP/1.1")))
+    }
+
+    #[test]
+    fn test_upgrade_required() {
+        let output = respond(&FnHandler{handle: upgrade_required}, &ServerConfig::new(),
+                             b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        // the protocols asked for survive the hop-by-hop headers being dropped
+        assert!(output.as_slice().contains("Upgrade: TLS/1.2, HTTP/1.1\r\n"));
+        assert!(output.as_slice().contains("Connection: keep-alive, upgrade\r\n"));
+        assert!(output.as_slice().ends_with("<h1>426 Upgrade Required</h1>"));
+    }
+
+    #[test]
+    fn test_host_route_beats_any_host() {
+        let mut router: Router<'static, MockStream, MockStream> = Router::new();
+        // registered first, yet only the fallback
+        router.add_route(GET, "/", box FnHandler{handle: hello});
+        router.add_host_route(Some("a.example"), GET, "/", box FnHandler{handle: show_host});
+        let config = ServerConfig::new();
+
+        let output = respond(&router, &config, b"GET / HTTP/1.1\r\nHost: A.example:8080\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\nA.example:8080 /"));
+        let output = respond(&router, &config, b"GET / HTTP/1.1\r\nHost: b.example\r\n\r\n");
+        assert!(output.as_slice().ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_concatenated_parts() {
+        let output = respond(&FnHandler{handle: three_parts}, &ServerConfig::new(),
+                             b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(output.as_slice().contains("Content-Length: 15\r\n"));
+        assert!(output.as_slice().ends_with("\r\n\r\none, two, three"));
+
+        // a part of unknown length leaves the whole unknown
+        let (_, streamed) = ChannelResponseWriter::new("text/plain");
+        let parts: Vec<Box<HTTPResponseWriter<MockStream>>> = vec![
+            BytesResponseWriter::<MockStream>::new(b"one, ".to_vec()),
+            streamed,
+        ];
+        let writer = ConcatResponseWriter::new(parts);
+        assert_eq!(writer.get_content_length(), None);
+        assert!(writer.is_streaming());
+    }
 }
@@ -0,0 +1,482 @@
+// Decoding of deflate data (RFC 1951), raw or wrapped the zlib (RFC 1950)
+// or the gzip (RFC 1952) way, e.g. for compressed request bodies. Output
+// is never allowed to grow past a given size, so that a small input
+// expanding to gigabytes fails early instead of exhausting memory.
+
+
+#[deriving(Show, PartialEq)]
+pub enum InflateError {
+    Malformed,
+    // the output would be larger than allowed
+    TooLarge,
+}
+
+
+pub type InflateResult<T> = Result<T, InflateError>;
+
+
+static LENGTH_BASE: [u16, ..29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+
+
+static LENGTH_EXTRA: [uint, ..29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+
+static DISTANCE_BASE: [u16, ..30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+
+static DISTANCE_EXTRA: [uint, ..30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+
+// The order code length code lengths are sent in.
+static CODE_LENGTH_ORDER: [uint, ..19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+
+// Raw deflate data, as sent with "Content-Encoding: deflate" by some
+// clients despite the standard asking for zlib.
+pub fn inflate(data: &[u8], max_size: uint) -> InflateResult<Vec<u8>> {
+    let (output, end) = try!(inflate_stream(data, 0, max_size));
+    if end != data.len() {
+        return Err(Malformed)
+    }
+    Ok(output)
+}
+
+
+pub fn zlib_decode(data: &[u8], max_size: uint) -> InflateResult<Vec<u8>> {
+    if !is_zlib_header(data) {
+        return Err(Malformed)
+    }
+    let (output, end) = try!(inflate_stream(data, 2, max_size));
+    if end + 4 != data.len() || read_u32_be(data, end) != adler32(output.as_slice()) {
+        return Err(Malformed)
+    }
+    Ok(output)
+}
+
+
+// Whether `data` starts like zlib data: deflate, a valid header checksum,
+// and no preset dictionary, which nothing here could supply.
+pub fn is_zlib_header(data: &[u8]) -> bool {
+    data.len() >= 2
+        && data[0] & 0x0f == 8
+        && ((data[0] as uint) << 8 | data[1] as uint) % 31 == 0
+        && data[1] & 0x20 == 0
+}
+
+
+// Several gzip members one after the other decode to their contents
+// concatenated.
+pub fn gunzip(data: &[u8], max_size: uint) -> InflateResult<Vec<u8>> {
+    let mut output = vec![];
+    let mut pos = 0u;
+    loop {
+        pos = try!(skip_gzip_header(data, pos));
+        let (member, end) = try!(inflate_stream(data, pos, max_size - output.len()));
+        if end + 8 > data.len()
+            || read_u32_le(data, end) != crc32(member.as_slice())
+            || read_u32_le(data, end + 4) != member.len() as u32
+        {
+            return Err(Malformed)
+        }
+        output.push_all(member.as_slice());
+        pos = end + 8;
+        if pos == data.len() {
+            return Ok(output)
+        }
+    }
+}
+
+
+// Where the deflate data of the gzip member starting at `pos` begins.
+fn skip_gzip_header(data: &[u8], pos: uint) -> InflateResult<uint> {
+    if data.len() < pos + 10 || data[pos] != 0x1f || data[pos + 1] != 0x8b || data[pos + 2] != 8 {
+        return Err(Malformed)
+    }
+    let flags = data[pos + 3];
+    let mut pos = pos + 10;
+    // extra fields
+    if flags & 4 != 0 {
+        if pos + 2 > data.len() {
+            return Err(Malformed)
+        }
+        pos += 2 + (data[pos] as uint | (data[pos + 1] as uint) << 8);
+    }
+    // zero terminated file name and comment
+    for flag in [8u8, 16].iter() {
+        if flags & *flag != 0 {
+            loop {
+                if pos >= data.len() {
+                    return Err(Malformed)
+                }
+                pos += 1;
+                if data[pos - 1] == 0 { break }
+            }
+        }
+    }
+    // header CRC
+    if flags & 2 != 0 {
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err(Malformed)
+    }
+    Ok(pos)
+}
+
+
+// Decodes the deflate data starting at `pos`, giving the output and where
+// the data ended.
+fn inflate_stream(data: &[u8], pos: uint, max_size: uint) -> InflateResult<(Vec<u8>, uint)> {
+    let mut output = vec![];
+    let mut bits = BitReader{data: data, pos: pos, bits: 0, count: 0};
+    loop {
+        let last = try!(bits.read(1)) == 1;
+        match try!(bits.read(2)) {
+            0 => try!(stored_block(&mut bits, &mut output, max_size)),
+            1 => {
+                let lengths: Vec<u8> = range(0u, 288).map(|symbol| match symbol {
+                    144 ... 255 => 9,
+                    256 ... 279 => 7,
+                    _ => 8,
+                }).collect();
+                let literals = try!(Huffman::new(lengths.as_slice()));
+                let distances = try!(Huffman::new(Vec::from_elem(30, 5u8).as_slice()));
+                try!(compressed_block(&mut bits, &mut output, &literals, &distances, max_size));
+            },
+            2 => {
+                let (literals, distances) = try!(dynamic_codes(&mut bits));
+                try!(compressed_block(&mut bits, &mut output, &literals, &distances, max_size));
+            },
+            _ => return Err(Malformed),
+        }
+        if last {
+            return Ok((output, bits.pos))
+        }
+    }
+}
+
+
+fn stored_block(bits: &mut BitReader, output: &mut Vec<u8>, max_size: uint) -> InflateResult<()> {
+    // stored data starts on a byte boundary
+    bits.bits = 0;
+    bits.count = 0;
+    let (data, pos) = (bits.data, bits.pos);
+    if pos + 4 > data.len() {
+        return Err(Malformed)
+    }
+    let length = data[pos] as uint | (data[pos + 1] as uint) << 8;
+    let complement = data[pos + 2] as uint | (data[pos + 3] as uint) << 8;
+    if length != !complement & 0xffff || pos + 4 + length > data.len() {
+        return Err(Malformed)
+    }
+    if output.len() + length > max_size {
+        return Err(TooLarge)
+    }
+    output.push_all(data.slice(pos + 4, pos + 4 + length));
+    bits.pos = pos + 4 + length;
+    Ok(())
+}
+
+
+fn dynamic_codes(bits: &mut BitReader) -> InflateResult<(Huffman, Huffman)> {
+    let literal_count = try!(bits.read(5)) as uint + 257;
+    let distance_count = try!(bits.read(5)) as uint + 1;
+    let code_count = try!(bits.read(4)) as uint + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(Malformed)
+    }
+
+    let mut code_lengths = [0u8, ..19];
+    for i in range(0, code_count) {
+        code_lengths[CODE_LENGTH_ORDER[i]] = try!(bits.read(3)) as u8;
+    }
+    let code = try!(Huffman::new(code_lengths.as_slice()));
+
+    let total = literal_count + distance_count;
+    let mut lengths: Vec<u8> = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let symbol = try!(code.decode(bits));
+        if symbol < 16 {
+            lengths.push(symbol as u8);
+            continue
+        }
+        let (length, repeat) = match symbol {
+            16 => match lengths.last() {
+                Some(&previous) => (previous, 3 + try!(bits.read(2)) as uint),
+                None => return Err(Malformed),
+            },
+            17 => (0, 3 + try!(bits.read(3)) as uint),
+            _ => (0, 11 + try!(bits.read(7)) as uint),
+        };
+        if lengths.len() + repeat > total {
+            return Err(Malformed)
+        }
+        for _ in range(0, repeat) {
+            lengths.push(length);
+        }
+    }
+    // a block without an end could never be decoded
+    if lengths.as_slice()[256] == 0 {
+        return Err(Malformed)
+    }
+    let literals = try!(Huffman::new(lengths.slice_to(literal_count)));
+    let distances = try!(Huffman::new(lengths.slice_from(literal_count)));
+    Ok((literals, distances))
+}
+
+
+fn compressed_block(bits: &mut BitReader, output: &mut Vec<u8>,
+                    literals: &Huffman, distances: &Huffman, max_size: uint)
+                    -> InflateResult<()>
+{
+    loop {
+        let symbol = try!(literals.decode(bits)) as uint;
+        if symbol < 256 {
+            if output.len() >= max_size {
+                return Err(TooLarge)
+            }
+            output.push(symbol as u8);
+            continue
+        }
+        if symbol == 256 {
+            return Ok(())
+        }
+
+        let symbol = symbol - 257;
+        if symbol >= 29 {
+            return Err(Malformed)
+        }
+        let length = LENGTH_BASE[symbol] as uint + try!(bits.read(LENGTH_EXTRA[symbol])) as uint;
+        let symbol = try!(distances.decode(bits)) as uint;
+        if symbol >= 30 {
+            return Err(Malformed)
+        }
+        let distance = DISTANCE_BASE[symbol] as uint + try!(bits.read(DISTANCE_EXTRA[symbol])) as uint;
+        if distance > output.len() {
+            return Err(Malformed)
+        }
+        if output.len() + length > max_size {
+            return Err(TooLarge)
+        }
+        // the copy may overlap what it appends
+        for _ in range(0, length) {
+            let b = output.as_slice()[output.len() - distance];
+            output.push(b);
+        }
+    }
+}
+
+
+// Reads the bits of `data` least significant first, as deflate packs them.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: uint,
+    bits: u32,
+    // number of bits in `bits`, always less than 8 between reads
+    count: uint,
+}
+
+
+impl <'a> BitReader<'a> {
+    fn read(&mut self, count: uint) -> InflateResult<u32> {
+        while self.count < count {
+            if self.pos >= self.data.len() {
+                return Err(Malformed)
+            }
+            self.bits |= (self.data[self.pos] as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.bits & ((1u32 << count) - 1);
+        self.bits >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+}
+
+
+// A canonical Huffman code given by the code length of every symbol.
+struct Huffman {
+    // number of codes of every length
+    counts: [u16, ..16],
+    // symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> InflateResult<Huffman> {
+        let mut counts = [0u16, ..16];
+        for length in lengths.iter() {
+            counts[*length as uint] += 1;
+        }
+        // more codes of some length than there are left is no prefix code
+        let mut left = 1i;
+        for length in range(1u, 16) {
+            left = (left << 1) - counts[length] as int;
+            if left < 0 {
+                return Err(Malformed)
+            }
+        }
+
+        let mut offsets = [0u, ..16];
+        for length in range(1u, 15) {
+            offsets[length + 1] = offsets[length] + counts[length] as uint;
+        }
+        let mut symbols = Vec::from_elem(lengths.len(), 0u16);
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbols.as_mut_slice()[offsets[*length as uint]] = symbol as u16;
+                offsets[*length as uint] += 1;
+            }
+        }
+        Ok(Huffman{counts: counts, symbols: symbols})
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> InflateResult<u16> {
+        // codes are sent most significant bit first
+        let (mut code, mut first, mut index) = (0i, 0i, 0i);
+        for length in range(1u, 16) {
+            code |= try!(bits.read(1)) as int;
+            let count = self.counts[length] as int;
+            if code - count < first {
+                return Ok(self.symbols.as_slice()[(index + code - first) as uint])
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Malformed)
+    }
+}
+
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data.iter() {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in range(0u, 8) {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+
+fn read_u32_be(data: &[u8], pos: uint) -> u32 {
+    (data[pos] as u32) << 24 | (data[pos + 1] as u32) << 16 | (data[pos + 2] as u32) << 8
+        | data[pos + 3] as u32
+}
+
+
+fn read_u32_le(data: &[u8], pos: uint) -> u32 {
+    data[pos] as u32 | (data[pos + 1] as u32) << 8 | (data[pos + 2] as u32) << 16
+        | (data[pos + 3] as u32) << 24
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{inflate, zlib_decode, gunzip, Malformed, TooLarge};
+
+    static TEXT: &'static [u8] = b"hello hello hello hello, deflate";
+
+    static GZIP: [u8, ..37] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48,
+        0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x75, 0x14, 0x52, 0x52, 0xd3,
+        0x72, 0x12, 0x4b, 0x52, 0x01, 0xd6, 0x3e, 0xcb, 0xf5, 0x20, 0x00, 0x00,
+        0x00,
+    ];
+
+    static ZLIB: [u8, ..25] = [
+        0x78, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x75,
+        0x14, 0x52, 0x52, 0xd3, 0x72, 0x12, 0x4b, 0x52, 0x01, 0xc4, 0x00, 0x0b,
+        0xd2,
+    ];
+
+    // a hundred thousand zeros, compressed with dynamic codes
+    static ZEROS: [u8, ..120] = [
+        0x78, 0xda, 0xed, 0xc1, 0x31, 0x01, 0x00, 0x00, 0x00, 0xc2, 0xa0, 0xf5,
+        0x4f, 0x6d, 0x0d, 0x0f, 0xa0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x57, 0x03, 0x86, 0xaf, 0x00, 0x01,
+    ];
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(gunzip(GZIP.as_slice(), 1000), Ok(TEXT.to_vec()));
+        assert_eq!(zlib_decode(ZLIB.as_slice(), 1000), Ok(TEXT.to_vec()));
+        // zlib without its two byte header and adler32
+        assert_eq!(inflate(ZLIB.slice(2, 21), 1000), Ok(TEXT.to_vec()));
+        // a stored block
+        assert_eq!(inflate([0x01, 0x06, 0x00, 0xf9, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64].as_slice(), 1000),
+                   Ok(b"stored".to_vec()));
+
+        let mut twice = GZIP.to_vec();
+        twice.push_all(GZIP.as_slice());
+        let mut expected = TEXT.to_vec();
+        expected.push_all(TEXT);
+        assert_eq!(gunzip(twice.as_slice(), 1000), Ok(expected));
+    }
+
+    #[test]
+    fn test_output_is_bounded() {
+        let zeros = zlib_decode(ZEROS.as_slice(), 100000).unwrap();
+        assert_eq!(zeros.len(), 100000);
+        assert!(zeros.iter().all(|b| *b == 0));
+        assert_eq!(zlib_decode(ZEROS.as_slice(), 99999), Err(TooLarge));
+        assert_eq!(gunzip(GZIP.as_slice(), TEXT.len() - 1), Err(TooLarge));
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert_eq!(gunzip(b"", 1000), Err(Malformed));
+        assert_eq!(gunzip(ZLIB.as_slice(), 1000), Err(Malformed));
+        assert_eq!(gunzip(GZIP.slice_to(30), 1000), Err(Malformed));
+        assert_eq!(zlib_decode(GZIP.as_slice(), 1000), Err(Malformed));
+        // trailing garbage
+        let mut longer = ZLIB.to_vec();
+        longer.push(0);
+        assert_eq!(zlib_decode(longer.as_slice(), 1000), Err(Malformed));
+
+        // a flipped bit in the data fails one check or another
+        for i in range(2u, ZLIB.len()) {
+            let mut corrupt = ZLIB.to_vec();
+            corrupt.as_mut_slice()[i] ^= 0x10;
+            assert!(zlib_decode(corrupt.as_slice(), 1000) != Ok(TEXT.to_vec()));
+        }
+        let mut corrupt = GZIP.to_vec();
+        corrupt.as_mut_slice()[30] ^= 1;
+        assert_eq!(gunzip(corrupt.as_slice(), 1000), Err(Malformed));
+    }
+}
@@ -3,16 +3,19 @@
 
 extern crate http_server2;
 
+use std::cmp::min;
+
 use std::collections::HashMap;
 
 use std::io::{
-    BufferedReader,
     BufferedWriter,
     IoResult,
 };
 
 use std::io::timer::sleep;
 
+use std::os::getenv;
+
 use std::rand::random;
 
 use std::sync::Arc;
@@ -22,26 +25,48 @@ use std::time::duration::Duration;
 use http_server2::{
     multi_thread_http_serve,
     green_http_serve,
+    BodyReader,
     BytesResponseWriter,
+    bytes_etag,
     HTTPRequestHandler,
-    HTTPMethod,
+    HTTPRequest,
     HTTPHeaders,
     HTTPResponseCode, HTTP200,
     HTTPResponseWriter,
+    ServerConfig,
 };
 
 
+// The hello world handler is tuned with HELLO_COUNT (lines counted down,
+// random from 1 to 10 when unset), HELLO_SLEEP_MS (pause between them) and
+// HELLO_CONTENT_LENGTH (0 to stream without a Content-Length).
 fn main() {
+    let count = getenv("HELLO_COUNT").and_then(|v| from_str::<u32>(v.as_slice()));
+    let sleep_ms = getenv("HELLO_SLEEP_MS").and_then(|v| from_str::<u32>(v.as_slice())).unwrap_or(1000);
+    let content_length = getenv("HELLO_CONTENT_LENGTH").map_or(true, |v| v.as_slice() != "0");
+
     // multi_thread_http_serve(
     green_http_serve(
         "127.0.0.1", 8080, Arc::new(
-        HelloWorldHTTPHandler
-        // StaticHandler{bytes: b"Hello world!\r\n".to_vec()}
-            )).unwrap();
+        HelloWorldHTTPHandler::new(count, sleep_ms, content_length)
+        // StaticHandler::new(b"Hello world!\r\n".to_vec())
+            ), ServerConfig::new()).unwrap();
 }
 
 
-struct HelloWorldHTTPHandler;
+struct HelloWorldHTTPHandler {
+    // a random count from 1 to 10 for every request when None
+    count: Option<u32>,
+    sleep: u32,
+    content_length: bool,
+}
+
+
+impl HelloWorldHTTPHandler {
+    fn new(count: Option<u32>, sleep: u32, content_length: bool) -> HelloWorldHTTPHandler {
+        HelloWorldHTTPHandler{count: count, sleep: sleep, content_length: content_length}
+    }
+}
 
 impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized>
     HTTPRequestHandler<'req, R, W>
@@ -49,22 +74,16 @@ impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized>
 {
     fn handle(
         &self,
-        method: HTTPMethod,
-        path: &Vec<u8>,
-        headers: &HTTPHeaders,
-        stream: BufferedReader<R>)
+        request: &HTTPRequest,
+        body: &mut BodyReader<R>)
         -> IoResult<Option<(HTTPResponseCode,
                             Box<HTTPHeaders>,
                             Box<HTTPResponseWriter<W> + 'req>)>>
     {
-        drop(stream);
-
-        let writer: Box<HTTPResponseWriter<W>> =
-            box StreamingHelloWorldResponseWriter{
-                count: random::<u32>() % 10 + 1,
-                sleep: 1000, content_length: true,
-                s: "Hello world!",
-            };
+        let writer: Box<HTTPResponseWriter<W>> = box StreamingHelloWorldResponseWriter::new(
+            self.count.unwrap_or_else(|| random::<u32>() % 10 + 1),
+            self.sleep,
+            self.content_length);
 
         Ok(Some((HTTP200, box HashMap::new(), writer)))
     }
@@ -79,20 +98,35 @@ struct StreamingHelloWorldResponseWriter<W: Writer + Send + Sized> {
 }
 
 
+impl <W: Writer + Send + Sized> StreamingHelloWorldResponseWriter<W> {
+    // Counts down from `count`, pausing `sleep` milliseconds after each line.
+    fn new(count: u32, sleep: u32, content_length: bool) -> StreamingHelloWorldResponseWriter<W> {
+        StreamingHelloWorldResponseWriter{
+            count: count,
+            sleep: sleep,
+            content_length: content_length,
+            s: "Hello world!",
+        }
+    }
+}
+
+
 impl <W: Writer + Send + Sized> HTTPResponseWriter<W>
     for StreamingHelloWorldResponseWriter<W>
 {
     fn get_content_length(&self) -> Option<u64> {
         if self.content_length {
+            // "N...\r\n" for every N, counted by the number of digits
+            let count = self.count as u64;
             let mut len = 0u64;
-            let mut exp = 1u64;
-            let mut val = 1u64;
-            while val * 10 < self.count as u64 {
-                val *= 10;
-                len += val * (exp + 5);
-                exp += 1;
+            let mut digits = 1u64;
+            let mut first = 1u64;
+            while first <= count {
+                let last = min(first * 10 - 1, count);
+                len += (last - first + 1) * (digits + 5);
+                first *= 10;
+                digits += 1;
             }
-            len += (self.count as u64 - val + 1) * (exp + 5);
 
             Some(len + self.s.len() as u64 + 2)
         } else {
@@ -104,7 +138,11 @@ impl <W: Writer + Send + Sized> HTTPResponseWriter<W>
         "text/html; charset=utf-8".to_string()
     }
 
-    fn write_data(&self, mut stream: BufferedWriter<W>) -> IoResult<()> {
+    fn is_streaming(&self) -> bool {
+        true
+    }
+
+    fn write_data(&self, stream: &mut BufferedWriter<W>) -> IoResult<()> {
         let mut count = self.count;
         while count > 0 {
             try!(stream.write_str(format!("{}...\r\n", count).as_slice()));
@@ -121,7 +159,16 @@ impl <W: Writer + Send + Sized> HTTPResponseWriter<W>
 
 
 pub struct StaticHandler {
-    bytes: Vec<u8>
+    bytes: Vec<u8>,
+    etag: Vec<u8>,
+}
+
+
+impl StaticHandler {
+    pub fn new(bytes: Vec<u8>) -> StaticHandler {
+        let etag = bytes_etag(bytes.as_slice());
+        StaticHandler{bytes: bytes, etag: etag}
+    }
 }
 
 
@@ -131,19 +178,50 @@ impl <'req, R: Reader + Send + Sized, W: Writer + Send + Sized>
 {
     fn handle(
         &self,
-        method: HTTPMethod,
-        path: &Vec<u8>,
-        headers: &HTTPHeaders,
-        stream: BufferedReader<R>)
+        request: &HTTPRequest,
+        body: &mut BodyReader<R>)
         -> IoResult<Option<(HTTPResponseCode,
                             Box<HTTPHeaders>,
                             Box<HTTPResponseWriter<W> + 'req>)>>
     {
-        drop(stream);
-
         let writer: Box<HTTPResponseWriter<W>> =
-            BytesResponseWriter::<W>::new(self.bytes.clone());
+            BytesResponseWriter::<W>::with_etag(self.bytes.clone(), self.etag.clone());
 
         Ok(Some((HTTP200, box HashMap::new(), writer)))
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufferedWriter, MemWriter};
+
+    use http_server2::HTTPResponseWriter;
+
+    use super::StreamingHelloWorldResponseWriter;
+
+    fn countdown(count: u32, content_length: bool) -> (Option<u64>, String) {
+        let writer: StreamingHelloWorldResponseWriter<MemWriter> =
+            StreamingHelloWorldResponseWriter::new(count, 0, content_length);
+        let mut stream = BufferedWriter::new(MemWriter::new());
+        writer.write_data(&mut stream).unwrap();
+        let output = stream.unwrap().unwrap();
+        (writer.get_content_length(), String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_countdown() {
+        let (length, output) = countdown(3, true);
+        assert_eq!(output, "3...\r\n2...\r\n1...\r\nHello world!\r\n".to_string());
+        assert_eq!(length, Some(output.len() as u64));
+
+        // lines of several lengths
+        for count in [9u32, 10, 11, 99, 100, 123].iter() {
+            let (length, output) = countdown(*count, true);
+            assert!(output.as_slice().starts_with(format!("{}...\r\n", count).as_slice()));
+            assert_eq!(length, Some(output.len() as u64));
+        }
+
+        assert_eq!(countdown(3, false).val0(), None);
+    }
+}
@@ -0,0 +1,176 @@
+// MD5 as specified by RFC 1321. Good for checksums such as Content-MD5,
+// but long broken as a cryptographic hash.
+
+use std::cmp::min;
+
+
+static SHIFTS: [uint, ..64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+    5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+    6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+
+static CONSTANTS: [u32, ..64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee,
+    0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be,
+    0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa,
+    0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+    0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c,
+    0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05,
+    0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039,
+    0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1,
+    0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+
+// Incremental hasher, for data arriving bit by bit.
+pub struct Md5 {
+    state: [u32, ..4],
+    // bytes not yet making up a whole block
+    pending: Vec<u8>,
+    length: u64,
+}
+
+
+impl Md5 {
+    pub fn new() -> Md5 {
+        Md5{
+            state: [0x67452301u32, 0xefcdab89, 0x98badcfe, 0x10325476],
+            pending: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.length += bytes.len() as u64;
+        let mut bytes = bytes;
+        if !self.pending.is_empty() {
+            let taken = min(64 - self.pending.len(), bytes.len());
+            self.pending.push_all(bytes.slice_to(taken));
+            bytes = bytes.slice_from(taken);
+            if self.pending.len() < 64 {
+                return
+            }
+            process_block(&mut self.state, self.pending.as_slice());
+            self.pending.clear();
+        }
+        for block in bytes.chunks(64) {
+            if block.len() == 64 {
+                process_block(&mut self.state, block);
+            } else {
+                self.pending.push_all(block);
+            }
+        }
+    }
+
+    // The digest of everything passed to `update` so far. More data may
+    // still be added afterwards.
+    pub fn digest(&self) -> [u8, ..16] {
+        let mut state = self.state;
+        let mut tail = self.pending.clone();
+        tail.push(0x80);
+        while tail.len() % 64 != 56 {
+            tail.push(0);
+        }
+        let bit_length = self.length * 8;
+        for i in range(0u, 8) {
+            tail.push((bit_length >> (8 * i)) as u8);
+        }
+        for block in tail.as_slice().chunks(64) {
+            process_block(&mut state, block);
+        }
+
+        let mut result = [0u8, ..16];
+        for (i, word) in state.iter().enumerate() {
+            for j in range(0u, 4) {
+                result[i * 4 + j] = (*word >> (8 * j)) as u8;
+            }
+        }
+        result
+    }
+}
+
+
+pub fn digest(bytes: &[u8]) -> [u8, ..16] {
+    let mut md5 = Md5::new();
+    md5.update(bytes);
+    md5.digest()
+}
+
+
+fn process_block(state: &mut [u32, ..4], block: &[u8]) {
+    let mut words = [0u32, ..16];
+    for i in range(0u, 16) {
+        words[i] = block[i * 4] as u32
+            | block[i * 4 + 1] as u32 << 8
+            | block[i * 4 + 2] as u32 << 16
+            | block[i * 4 + 3] as u32 << 24;
+    }
+
+    let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+    for i in range(0u, 64) {
+        let (f, g) = match i / 16 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+        let sum = a + f + CONSTANTS[i] + words[g];
+        a = d;
+        d = c;
+        c = b;
+        b = b + rotate_left(sum, SHIFTS[i]);
+    }
+
+    state[0] += a;
+    state[1] += b;
+    state[2] += c;
+    state[3] += d;
+}
+
+
+fn rotate_left(x: u32, n: uint) -> u32 {
+    (x << n) | (x >> (32 - n))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{Md5, digest};
+
+    fn hex(bytes: &[u8]) -> String {
+        let digits: Vec<String> = bytes.iter().map(|b| format!("{:02x}", *b)).collect();
+        digits.concat()
+    }
+
+    // from RFC 1321's test suite
+    #[test]
+    fn test_rfc_vectors() {
+        assert_eq!(hex(digest(b"").as_slice()).as_slice(), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(digest(b"abc").as_slice()).as_slice(), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(digest(b"message digest").as_slice()).as_slice(),
+                   "f96b697d7cb7938d525a2f31aaf161d0");
+        assert_eq!(hex(digest(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890").as_slice()).as_slice(),
+                   "57edf4a22be3c955ac49da2e2107b67a");
+    }
+
+    #[test]
+    fn test_incremental() {
+        let data = b"The quick brown fox jumps over the lazy dog, more than one block's worth of it";
+        for split in range(0u, data.len()) {
+            let mut md5 = Md5::new();
+            md5.update(data.slice_to(split));
+            md5.update(data.slice_from(split));
+            assert_eq!(md5.digest().as_slice(), digest(data).as_slice());
+        }
+    }
+}